@@ -4,15 +4,24 @@ use actix_web::{
 };
 use clap::{Arg, Command};
 use env_logger::Env;
+use log::info;
 use parking_lot::Mutex;
 use serde::Deserialize;
 use serde_json::json;
-use std::time::Duration;
+use std::{
+    collections::HashSet,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 struct AppState {
+    port: u16,
     sleep_duration: Duration,
     heartbeat_id: Mutex<u32>,
     next_queue_id: Mutex<u32>,
+    health_changes: Mutex<Vec<serde_json::Value>>,
+    queues: Mutex<HashSet<String>>,
+    active_handlers: AtomicU64,
 }
 
 #[derive(Deserialize)]
@@ -33,9 +42,11 @@ async fn create_queue(form: web::Form<QueueIdForm>, data: web::Data<AppState>) -
         None => {
             let mut queue_int = data.next_queue_id.lock();
             *queue_int += 1;
-            format!("{queue_int}:1")
+            // Unique across shards, as Tornado's are
+            format!("{}:{queue_int}", data.port)
         }
     };
+    data.queues.lock().insert(queue_id.clone());
     let resp = json!({"result":"success","msg":"","events":[], "queue_id": queue_id});
     HttpResponse::Ok()
         .append_header(("x-tornado-queue-id", queue_id))
@@ -44,8 +55,9 @@ async fn create_queue(form: web::Form<QueueIdForm>, data: web::Data<AppState>) -
 }
 
 #[delete("/events")]
-async fn delete_queue(form: web::Form<QueueIdForm>) -> impl Responder {
+async fn delete_queue(form: web::Form<QueueIdForm>, data: web::Data<AppState>) -> impl Responder {
     let queue_id = form.queue_id.clone().expect("No queue-id");
+    data.queues.lock().remove(&queue_id);
     let resp = json!({"result":"success","msg":""});
     HttpResponse::Ok()
         .content_type(ContentType::json())
@@ -60,7 +72,9 @@ async fn poll_events(
 ) -> impl Responder {
     let queue_id = parameters.queue_id.clone();
     if parameters.dont_block == "false" {
+        data.active_handlers.fetch_add(1, Ordering::Relaxed);
         tokio::time::sleep(data.sleep_duration).await;
+        data.active_handlers.fetch_sub(1, Ordering::Relaxed);
     }
     let mut heartbeat_id = data.heartbeat_id.lock();
     *heartbeat_id += 1;
@@ -76,6 +90,46 @@ async fn poll_events(
         .body(resp.to_string())
 }
 
+// The load summary which kansas's health checker can fetch
+#[get("/load")]
+async fn load(data: web::Data<AppState>) -> impl Responder {
+    let resp = json!({
+        "queues": data.queues.lock().len(),
+        "handlers": data.active_handlers.load(Ordering::Relaxed),
+        "memory_bytes": resident_memory(),
+    });
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(resp.to_string())
+}
+
+// Resident set size, in bytes, if we are on Linux
+fn resident_memory() -> u64 {
+    std::fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split(' ').nth(1)?.parse::<u64>().ok())
+        .map_or(0, |pages| pages * 4096)
+}
+
+// Stands in for whatever kansas's health webhooks would be sent to,
+// remembering what it was sent so that tests can GET it back.
+#[post("/kansas/health-hook")]
+async fn record_health_change(
+    change: web::Json<serde_json::Value>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    info!("Health change: {}", change);
+    data.health_changes.lock().push(change.into_inner());
+    HttpResponse::Ok().finish()
+}
+
+#[get("/kansas/health-hook")]
+async fn health_changes(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(json!(*data.health_changes.lock()).to_string())
+}
+
 #[actix_web::main] // or #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let matches = Command::new("Kansas")
@@ -115,9 +169,13 @@ async fn main() -> std::io::Result<()> {
             .unwrap(),
     );
     let state = web::Data::new(AppState {
+        port,
         sleep_duration,
         heartbeat_id: Mutex::new(0),
         next_queue_id: Mutex::new(0),
+        health_changes: Mutex::new(Vec::new()),
+        queues: Mutex::new(HashSet::new()),
+        active_handlers: AtomicU64::new(0),
     });
 
     env_logger::init_from_env(Env::default().default_filter_or("info"));
@@ -126,6 +184,9 @@ async fn main() -> std::io::Result<()> {
             .wrap(Logger::default())
             .app_data(state.clone())
             .route("/health", web::get().to(|| async { "OK!" }))
+            .service(load)
+            .service(record_health_change)
+            .service(health_changes)
             .service(
                 web::scope("/json")
                     .service(create_queue)
@@ -0,0 +1,149 @@
+use crate::{handler::RouteAction, metrics};
+use hyper::{Body, Method, Request, Response};
+use log::error;
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use url::form_urlencoded;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccessLogDestination {
+    Stdout,
+    File(PathBuf),
+}
+
+// Entries waiting to be written; beyond this, new ones are dropped
+// rather than holding up requests.
+const QUEUED_ENTRIES: usize = 10_000;
+
+// Entries are written by a thread of their own, so that a slow disk or
+// pipe never blocks the runtime.
+pub struct AccessLog {
+    sender: SyncSender<Vec<u8>>,
+    get_sample_rate: f64,
+}
+
+impl AccessLog {
+    pub fn open(destination: &AccessLogDestination, get_sample_rate: f64) -> io::Result<AccessLog> {
+        let writer: Box<dyn Write + Send> = match destination {
+            AccessLogDestination::Stdout => Box::new(io::stdout()),
+            AccessLogDestination::File(path) => {
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUEUED_ENTRIES);
+        thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || write_entries(receiver, BufWriter::new(writer)))?;
+        Ok(AccessLog {
+            sender,
+            get_sample_rate,
+        })
+    }
+
+    pub fn record(&self, entry: &AccessLogEntry) {
+        // Successful long-polls are the bulk of the traffic, and
+        // the least interesting; everything else is always logged.
+        if entry.method == Method::GET.as_str()
+            && entry.error.is_none()
+            && rand::random::<f64>() >= self.get_sample_rate
+        {
+            return;
+        }
+
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize access log entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => metrics::ACCESS_LOG_DROPPED.inc(),
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Access log writer has stopped");
+            }
+        }
+    }
+}
+
+// Writes whatever has queued up, then flushes, so that lines are not
+// held back once things go quiet.
+fn write_entries(receiver: Receiver<Vec<u8>>, mut writer: BufWriter<Box<dyn Write + Send>>) {
+    while let Ok(line) = receiver.recv() {
+        let mut result = writer.write_all(&line);
+        while let Ok(line) = receiver.try_recv() {
+            result = result.and_then(|_| writer.write_all(&line));
+        }
+        if let Err(e) = result.and_then(|_| writer.flush()) {
+            error!("Failed to write access log entries: {}", e);
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    pub timestamp: f64,
+    pub request_id: String,
+    pub client_ip: IpAddr,
+    pub method: String,
+    pub path: String,
+    pub queue_id: Option<String>,
+    pub backend: Option<String>,
+    pub action: Option<RouteAction>,
+    pub status: u16,
+    pub latency_seconds: f64,
+    pub error: Option<&'static str>,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl AccessLogEntry {
+    pub fn new(request: &Request<Body>, request_id: &str, client_ip: IpAddr) -> AccessLogEntry {
+        let queue_id = match *request.method() {
+            Method::GET => request.uri().query().and_then(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .find(|pair| pair.0 == "queue_id")
+                    .map(|pair| pair.1.into_owned())
+            }),
+            _ => None,
+        };
+        AccessLogEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64()),
+            request_id: request_id.to_string(),
+            client_ip,
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+            queue_id,
+            backend: None,
+            action: None,
+            status: 0,
+            latency_seconds: 0.0,
+            error: None,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn finish(&mut self, response: &Response<Body>) {
+        self.status = response.status().as_u16();
+        self.latency_seconds = self.start.elapsed().as_secs_f64();
+        if let Some(queue_id) = response
+            .headers()
+            .get("x-tornado-queue-id")
+            .and_then(|value| value.to_str().ok())
+        {
+            self.queue_id = Some(queue_id.to_string());
+        }
+    }
+}
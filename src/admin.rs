@@ -0,0 +1,230 @@
+use crate::{configuration::RuntimeConfig, handler::client_ip, metrics};
+use futures::{Future, TryFutureExt};
+use hyper::{
+    header::AUTHORIZATION, server::conn::AddrStream, service::make_service_fn, service::Service,
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use ipnet::IpNet;
+use log::warn;
+use serde_json::{json, Map, Value};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::runtime::Handle;
+
+pub struct AdminConfig {
+    pub enabled: bool,
+    pub listen_address: SocketAddr,
+    pub allowed_networks: Vec<IpNet>,
+    pub bearer_token: Option<String>,
+}
+
+impl AdminConfig {
+    fn is_allowed_address(&self, client_address: &SocketAddr) -> bool {
+        let ip = client_ip(client_address);
+        self.allowed_networks.is_empty()
+            || self.allowed_networks.iter().any(|net| net.contains(&ip))
+    }
+
+    fn is_authorized(&self, request: &Request<Body>) -> bool {
+        match &self.bearer_token {
+            None => true,
+            Some(token) => {
+                match request
+                    .headers()
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+                {
+                    Some(given) => constant_time_eq(given, token.as_bytes()),
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+// Takes as long wherever the first difference is, so that the token
+// cannot be guessed a byte at a time from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub struct AdminService {
+    pub client_address: SocketAddr,
+    pub config: Arc<RuntimeConfig>,
+}
+
+impl Service<Request<Body>> for AdminService {
+    type Response = Response<Body>;
+    type Error = hyper::Error;
+
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let admin = &self.config.admin;
+        if !admin.is_allowed_address(&self.client_address) {
+            warn!(
+                "Refused admin request from {} for {}",
+                self.client_address,
+                request.uri()
+            );
+            return Box::pin(async { Ok(status_response(StatusCode::FORBIDDEN)) });
+        }
+        if !admin.is_authorized(&request) {
+            return Box::pin(async {
+                Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("WWW-Authenticate", "Bearer")
+                    .body(Body::empty())
+                    .unwrap())
+            });
+        }
+
+        match (request.method(), request.uri().path()) {
+            (_, "/metrics") => Box::pin(async move { metrics::handler() }),
+            (_, "/debug/tasks") => {
+                let response = task_dump(&self.config);
+                Box::pin(async { Ok(response) })
+            }
+            (&Method::GET, "/backends") => {
+                let response = backends(&self.config);
+                Box::pin(async { Ok(response) })
+            }
+            (&Method::PUT, path) if is_weight_path(path) => {
+                let config = Arc::clone(&self.config);
+                Box::pin(async move { set_weight(&config, request).await })
+            }
+            _ => Box::pin(async { Ok(status_response(StatusCode::NOT_FOUND)) }),
+        }
+    }
+}
+
+// Each backend's state as placement sees it.  Weights only matter for
+// queues placed by a policy which uses them; queues which Django names a
+// shard for in `x-tornado-shard` always go there.
+fn backends(config: &RuntimeConfig) -> Response<Body> {
+    let backends: Map<String, Value> = config
+        .backend
+        .addresses
+        .iter()
+        .map(|(address, backend)| {
+            let state = json!({
+                "health": metrics::health_state_label(&backend.healthiness.load()),
+                "circuit": backend.circuit_breaker.state().to_string(),
+                "weight": backend.weight(),
+                "queues": backend.load.load().as_ref().map(|load| load.queues),
+            });
+            (address.clone(), state)
+        })
+        .collect();
+    json_response(json!({
+        "weights_used": config.placement.config.policy.uses_weights(),
+        "backends": backends,
+    }))
+}
+
+fn is_weight_path(path: &str) -> bool {
+    path.starts_with("/backends/") && path.ends_with("/weight")
+}
+
+// `PUT /backends/<host:port>/weight` with the new weight as the body.
+// This lasts until the next reload or restart, so lasting changes
+// belong in the configuration file too.
+async fn set_weight(
+    config: &RuntimeConfig,
+    request: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    let address = request.uri().path()["/backends/".len()..]
+        .trim_end_matches("/weight")
+        .to_string();
+    let backend = match config.backend.addresses.get(&address) {
+        Some(backend) => backend,
+        None => return Ok(status_response(StatusCode::NOT_FOUND)),
+    };
+    let body = hyper::body::to_bytes(request.into_body()).await?;
+    let weight = match std::str::from_utf8(&body)
+        .ok()
+        .and_then(|body| body.trim().parse::<u32>().ok())
+    {
+        Some(weight) => weight,
+        None => return Ok(status_response(StatusCode::BAD_REQUEST)),
+    };
+    backend.set_weight(&address, weight);
+    Ok(json_response(json!({ "weight": weight })))
+}
+
+// A snapshot of what the runtime is busy with, for debugging stuck
+// requests.  Long-polls are redirected to nginx rather than held open
+// here, so only forwarded requests appear as in flight.
+fn task_dump(config: &RuntimeConfig) -> Response<Body> {
+    let runtime = Handle::current().metrics();
+    let in_flight: Map<String, Value> = config
+        .backend
+        .addresses
+        .keys()
+        .map(|backend| {
+            let count = metrics::BACKEND_IN_FLIGHT
+                .with_label_values(&[backend])
+                .get();
+            (backend.clone(), count.into())
+        })
+        .collect();
+    json_response(json!({
+        "workers": runtime.num_workers(),
+        "alive_tasks": runtime.num_alive_tasks(),
+        "open_requests": metrics::OPEN_CONNECTIONS.get(),
+        "in_flight_by_backend": in_flight,
+    }))
+}
+
+fn json_response(value: Value) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub async fn create(config: Arc<RuntimeConfig>) -> Result<(), io::Error> {
+    if !config.admin.enabled {
+        return Ok(());
+    }
+    let address = config.admin.listen_address;
+    let service = make_service_fn(move |stream: &AddrStream| {
+        let client_address = stream.remote_addr();
+        let config = Arc::clone(&config);
+
+        async move {
+            Ok::<_, io::Error>(AdminService {
+                client_address,
+                config,
+            })
+        }
+    });
+    Server::try_bind(&address)
+        .map_err(|e| {
+            let msg = format!("Failed to listen admin server: {}", e);
+            io::Error::other(msg)
+        })?
+        .serve(service)
+        .map_err(|e| {
+            let msg = format!("Failed to serve admin server: {}", e);
+            io::Error::other(msg)
+        })
+        .await
+}
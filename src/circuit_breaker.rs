@@ -0,0 +1,305 @@
+use crate::metrics;
+use log::warn;
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// The window is kept as this many buckets, so that old results can be
+// dropped without remembering every request.
+const WINDOW_BUCKETS: u32 = 10;
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    // Results older than this are forgotten
+    pub window: Duration,
+    // Too few requests in the window say nothing about the error rate
+    pub min_requests: u32,
+    pub error_rate: f64,
+    // How long to refuse requests before trying the backend again
+    pub open_duration: Duration,
+    // Trial requests which must all succeed to close the circuit again
+    pub half_open_requests: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half-open"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    start: Instant,
+    successes: u32,
+    failures: u32,
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: CircuitState,
+    // When the circuit last opened or went half-open
+    since: Instant,
+    window: VecDeque<Bucket>,
+    trials: u32,
+    trial_successes: u32,
+}
+
+// Stops sending requests to a backend whose forwarded requests are
+// failing, even if its health checks pass, and lets a few through once
+// it has had time to recover.  A backend which fails its health checks
+// gets no requests at all, so trials only start once those pass again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    breaker: Mutex<Breaker>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            config,
+            breaker: Mutex::new(Breaker {
+                state: CircuitState::Closed,
+                since: Instant::now(),
+                window: VecDeque::new(),
+                trials: 0,
+                trial_successes: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.breaker.lock().unwrap().state
+    }
+
+    // Whether requests which we will not see the result of, such as
+    // redirects, may go to the backend.  They are not trials.
+    pub fn is_open(&self) -> bool {
+        let breaker = self.breaker.lock().unwrap();
+        breaker.state == CircuitState::Open && breaker.since.elapsed() < self.config.open_duration
+    }
+
+    // Whether a forwarded request may go to the backend; when half-open,
+    // this uses up one of the trial requests.
+    pub fn allow(&self, backend: &str) -> bool {
+        let mut breaker = self.breaker.lock().unwrap();
+        match breaker.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                if breaker.since.elapsed() < self.config.open_duration {
+                    return false;
+                }
+                self.transition(&mut breaker, backend, CircuitState::HalfOpen);
+                breaker.trials = 1;
+                true
+            }
+            CircuitState::HalfOpen => {
+                // Trials which never finished, such as those whose client
+                // went away, must not hold the circuit half-open forever
+                if breaker.trials >= self.config.half_open_requests
+                    && breaker.since.elapsed() >= self.config.open_duration
+                {
+                    self.transition(&mut breaker, backend, CircuitState::HalfOpen);
+                }
+                if breaker.trials >= self.config.half_open_requests {
+                    return false;
+                }
+                breaker.trials += 1;
+                true
+            }
+        }
+    }
+
+    // Records the result of a forwarded request
+    pub fn record(&self, backend: &str, success: bool) {
+        let mut breaker = self.breaker.lock().unwrap();
+        match breaker.state {
+            CircuitState::Closed => {
+                let now = Instant::now();
+                let bucket_width = self.config.window / WINDOW_BUCKETS;
+                while let Some(bucket) = breaker.window.front() {
+                    if now.duration_since(bucket.start) < self.config.window {
+                        break;
+                    }
+                    breaker.window.pop_front();
+                }
+                match breaker.window.back_mut() {
+                    Some(bucket) if now.duration_since(bucket.start) < bucket_width => {
+                        bucket.add(success)
+                    }
+                    _ => {
+                        let mut bucket = Bucket {
+                            start: now,
+                            successes: 0,
+                            failures: 0,
+                        };
+                        bucket.add(success);
+                        breaker.window.push_back(bucket);
+                    }
+                }
+
+                let (successes, failures) = breaker
+                    .window
+                    .iter()
+                    .fold((0, 0), |(s, f), b| (s + b.successes, f + b.failures));
+                let total = successes + failures;
+                if total >= self.config.min_requests
+                    && f64::from(failures) >= self.config.error_rate * f64::from(total)
+                {
+                    self.transition(&mut breaker, backend, CircuitState::Open);
+                }
+            }
+            CircuitState::HalfOpen if !success => {
+                self.transition(&mut breaker, backend, CircuitState::Open)
+            }
+            CircuitState::HalfOpen => {
+                breaker.trial_successes += 1;
+                if breaker.trial_successes >= self.config.half_open_requests {
+                    self.transition(&mut breaker, backend, CircuitState::Closed);
+                }
+            }
+            // Requests which were let through before the circuit opened
+            CircuitState::Open => {}
+        }
+    }
+
+    fn transition(&self, breaker: &mut Breaker, backend: &str, state: CircuitState) {
+        if breaker.state != state {
+            warn!("Circuit breaker for {} is now {}", backend, state);
+            metrics::set_circuit_state(backend, state);
+            metrics::BACKEND_CIRCUIT_TRANSITIONS
+                .with_label_values(&[backend, &state.to_string()])
+                .inc();
+        }
+        breaker.state = state;
+        breaker.since = Instant::now();
+        breaker.window.clear();
+        breaker.trials = 0;
+        breaker.trial_successes = 0;
+    }
+}
+
+impl Bucket {
+    fn add(&mut self, success: bool) {
+        if success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    const BACKEND: &str = "127.0.0.1:9800";
+    const OPEN_DURATION: Duration = Duration::from_millis(20);
+
+    fn breaker(min_requests: u32, error_rate: f64, half_open_requests: u32) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            window: Duration::from_secs(60),
+            min_requests,
+            error_rate,
+            open_duration: OPEN_DURATION,
+            half_open_requests,
+        })
+    }
+
+    fn record_all(breaker: &CircuitBreaker, results: &[bool]) {
+        for &success in results {
+            breaker.record(BACKEND, success);
+        }
+    }
+
+    // Opens the circuit, then waits until trials may start
+    fn open(breaker: &CircuitBreaker) {
+        record_all(breaker, &[false; 4]);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        sleep(OPEN_DURATION);
+    }
+
+    #[test]
+    fn opens_only_with_enough_requests() {
+        let breaker = breaker(4, 0.5, 1);
+        record_all(&breaker, &[false, false, false]);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record(BACKEND, true);
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn opens_at_the_error_rate() {
+        let breaker = breaker(4, 0.5, 1);
+        record_all(&breaker, &[true, true, true, false, false]);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        breaker.record(BACKEND, false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[test]
+    fn refuses_until_the_open_duration_passes() {
+        let breaker = breaker(4, 0.5, 1);
+        record_all(&breaker, &[false; 4]);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow(BACKEND));
+        sleep(OPEN_DURATION);
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(BACKEND));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    }
+
+    #[test]
+    fn closes_after_the_trials_succeed() {
+        let breaker = breaker(4, 0.5, 2);
+        open(&breaker);
+        assert!(breaker.allow(BACKEND));
+        assert!(breaker.allow(BACKEND));
+        assert!(!breaker.allow(BACKEND));
+        breaker.record(BACKEND, true);
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record(BACKEND, true);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow(BACKEND));
+    }
+
+    #[test]
+    fn reopens_on_a_failed_trial() {
+        let breaker = breaker(4, 0.5, 2);
+        open(&breaker);
+        assert!(breaker.allow(BACKEND));
+        breaker.record(BACKEND, false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow(BACKEND));
+    }
+
+    #[test]
+    fn recovers_when_trials_never_finish() {
+        let breaker = breaker(4, 0.5, 2);
+        open(&breaker);
+        assert!(breaker.allow(BACKEND));
+        assert!(breaker.allow(BACKEND));
+        assert!(!breaker.allow(BACKEND));
+        sleep(OPEN_DURATION);
+        assert!(breaker.allow(BACKEND));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        breaker.record(BACKEND, true);
+        breaker.record(BACKEND, true);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+}
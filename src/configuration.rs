@@ -1,10 +1,39 @@
 use crate::{
-    handler::{BackendPool, BackendPoolBuilder},
-    health::{HealthConfig, Healthiness},
+    access_log::{AccessLog, AccessLogDestination},
+    admin::AdminConfig,
+    circuit_breaker::CircuitBreakerConfig,
+    handler::{Backend, BackendPool, BackendPoolBuilder, DEFAULT_WEIGHT},
+    headers::ForwardingConfig,
+    health::HealthConfig,
+    hooks::HealthHooksConfig,
+    metrics::MetricsConfig,
+    placement::{
+        ConsistentHash, ExplicitHeader, LeastLoaded, Pinned, Placement, PlacementConfig,
+        PlacementPolicy, WeightedRandom,
+    },
+    queue_store::{JournalQueueStore, MemoryQueueStore, QueueStore},
+    telemetry::TracingConfig,
+    tls::{BackendTlsConfig, TlsConfig},
 };
-use arc_swap::ArcSwap;
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    http::uri::Scheme,
+    StatusCode, Uri,
+};
+use log::{error, info, warn};
+use rustls::{ClientConfig, RootCertStore};
 use serde::Deserialize;
-use std::{error::Error, fmt::Debug, fs, io, net::SocketAddr, path::Path, time::Duration};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Debug,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
 
 pub async fn read_initial_config<P: AsRef<Path>>(path: P) -> Result<RuntimeConfig, io::Error> {
     read_runtime_config(&path).await.map_err(|e| {
@@ -21,13 +50,70 @@ where
 {
     let config = TomlConfig::read(&path)?;
     let listen_address = config.listen_address.parse().map_err(invalid_data)?;
+    let admin = config.admin.try_into()?;
+    let metrics = config.metrics.try_into()?;
+    let access_log = config.access_log.map(AccessLog::try_from).transpose()?;
+    let forwarding = config.forwarding.try_into()?;
+    let health_hooks = config.health_hooks.try_into()?;
+    let backend: BackendPool = config.backend.try_into()?;
+    let placement = config.placement.into_config(&backend)?;
+    let queue_store = config.queue_store.try_into()?;
 
     Ok(RuntimeConfig {
         listen_address,
-        backend: config.backend.into(),
+        proxy_protocol: config.proxy_protocol,
+        tls: config.tls.map(TlsConfig::from),
+        admin,
+        metrics,
+        tracing: config.tracing.into(),
+        access_log,
+        forwarding,
+        health_hooks,
+        placement: Placement::new(placement),
+        queue_store,
+        backend,
     })
 }
 
+// Backends' weights are the only configuration applied on SIGHUP; other
+// changes need a restart.
+pub async fn reload_weights_on_sighup(
+    config: Arc<RuntimeConfig>,
+    path: PathBuf,
+) -> Result<(), io::Error> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match reload_weights(&config.backend, &path) {
+            Ok(()) => info!("Reloaded backend weights"),
+            Err(e) => error!("Failed to reload backend weights: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn reload_weights(pool: &BackendPool, path: &Path) -> Result<(), io::Error> {
+    let mut config = TomlConfig::read(path)?;
+    let weights = config.backend.take_weights()?;
+    // Every address is checked before any weight changes, so that a bad
+    // file changes nothing
+    let mut updates = Vec::new();
+    for (address, weight) in weights {
+        let uri: Uri = address.parse().map_err(invalid_data)?;
+        let authority = uri
+            .authority()
+            .ok_or_else(|| invalid_data(format!("Missing backend host: {}", address)))?
+            .to_string();
+        match pool.addresses.get_key_value(&authority) {
+            Some((authority, backend)) => updates.push((authority, backend, weight)),
+            None => warn!("Ignoring new backend {} until restart", address),
+        }
+    }
+    for (authority, backend, weight) in updates {
+        backend.set_weight(authority, weight);
+    }
+    Ok(())
+}
+
 fn invalid_data<E>(error: E) -> io::Error
 where
     E: Into<Box<dyn Error + Send + Sync>>,
@@ -37,6 +123,16 @@ where
 
 pub struct RuntimeConfig {
     pub listen_address: SocketAddr,
+    pub proxy_protocol: bool,
+    pub tls: Option<TlsConfig>,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+    pub tracing: TracingConfig,
+    pub access_log: Option<AccessLog>,
+    pub forwarding: ForwardingConfig,
+    pub health_hooks: HealthHooksConfig,
+    pub placement: Placement,
+    pub queue_store: Box<dyn QueueStore>,
     pub backend: BackendPool,
 }
 
@@ -44,6 +140,24 @@ pub struct RuntimeConfig {
 struct TomlConfig {
     #[serde(default = "default_listen_address")]
     listen_address: String,
+    #[serde(default)]
+    proxy_protocol: bool,
+    tls: Option<TlsTomlConfig>,
+    #[serde(default)]
+    admin: AdminTomlConfig,
+    #[serde(default)]
+    metrics: MetricsTomlConfig,
+    #[serde(default)]
+    tracing: TracingTomlConfig,
+    access_log: Option<AccessLogTomlConfig>,
+    #[serde(default)]
+    forwarding: ForwardingTomlConfig,
+    #[serde(default)]
+    health_hooks: HealthHooksTomlConfig,
+    #[serde(default)]
+    placement: PlacementTomlConfig,
+    #[serde(default)]
+    queue_store: QueueStoreTomlConfig,
     backend: BackendPoolConfig,
 }
 
@@ -51,6 +165,198 @@ fn default_listen_address() -> String {
     "127.0.0.1:9799".to_string()
 }
 
+#[derive(Debug, Deserialize)]
+struct TlsTomlConfig {
+    certificate: String,
+    key: String,
+}
+
+impl From<TlsTomlConfig> for TlsConfig {
+    fn from(other: TlsTomlConfig) -> Self {
+        TlsConfig {
+            certificate_path: other.certificate.into(),
+            key_path: other.key.into(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminTomlConfig {
+    #[serde(default = "default_admin_enabled")]
+    enabled: bool,
+    #[serde(default = "default_admin_listen_address")]
+    listen_address: String,
+    #[serde(default = "default_admin_allowed_networks")]
+    allowed_networks: Vec<String>,
+    bearer_token: Option<String>,
+}
+
+impl Default for AdminTomlConfig {
+    fn default() -> Self {
+        AdminTomlConfig {
+            enabled: default_admin_enabled(),
+            listen_address: default_admin_listen_address(),
+            allowed_networks: default_admin_allowed_networks(),
+            bearer_token: None,
+        }
+    }
+}
+
+fn default_admin_enabled() -> bool {
+    true
+}
+
+fn default_admin_listen_address() -> String {
+    "127.0.0.1:9798".to_string()
+}
+
+// Only loopback unless told otherwise, as the admin API can change
+// weights; an explicit empty list allows everyone
+fn default_admin_allowed_networks() -> Vec<String> {
+    vec!["127.0.0.0/8".to_string(), "::1/128".to_string()]
+}
+
+impl TryFrom<AdminTomlConfig> for AdminConfig {
+    type Error = io::Error;
+
+    fn try_from(other: AdminTomlConfig) -> Result<Self, Self::Error> {
+        let allowed_networks = other
+            .allowed_networks
+            .iter()
+            .map(|network| network.parse())
+            .collect::<Result<_, _>>()
+            .map_err(invalid_data)?;
+        Ok(AdminConfig {
+            enabled: other.enabled,
+            listen_address: other.listen_address.parse().map_err(invalid_data)?,
+            allowed_networks,
+            bearer_token: other.bearer_token,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct MetricsTomlConfig {
+    redirect_buckets: Option<Vec<f64>>,
+    forward_buckets: Option<Vec<f64>>,
+    backend_buckets: Option<Vec<f64>>,
+}
+
+impl TryFrom<MetricsTomlConfig> for MetricsConfig {
+    type Error = io::Error;
+
+    fn try_from(other: MetricsTomlConfig) -> Result<Self, Self::Error> {
+        let defaults = MetricsConfig::default();
+        Ok(MetricsConfig {
+            redirect_buckets: histogram_buckets(other.redirect_buckets, defaults.redirect_buckets)?,
+            forward_buckets: histogram_buckets(other.forward_buckets, defaults.forward_buckets)?,
+            backend_buckets: histogram_buckets(other.backend_buckets, defaults.backend_buckets)?,
+        })
+    }
+}
+
+fn histogram_buckets(buckets: Option<Vec<f64>>, default: Vec<f64>) -> Result<Vec<f64>, io::Error> {
+    match buckets {
+        None => Ok(default),
+        Some(buckets) if buckets.is_empty() => {
+            Err(invalid_data("Histogram buckets must not be empty"))
+        }
+        Some(buckets) if buckets.windows(2).any(|pair| pair[0] >= pair[1]) => {
+            Err(invalid_data(format!(
+                "Histogram buckets must be strictly increasing: {:?}",
+                buckets
+            )))
+        }
+        Some(buckets) => Ok(buckets),
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct TracingTomlConfig {
+    otlp_endpoint: Option<String>,
+    service_name: Option<String>,
+    #[serde(default)]
+    console: bool,
+}
+
+impl From<TracingTomlConfig> for TracingConfig {
+    fn from(other: TracingTomlConfig) -> Self {
+        TracingConfig {
+            otlp_endpoint: other.otlp_endpoint,
+            service_name: other.service_name.unwrap_or_else(|| "kansas".to_string()),
+            console: other.console,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AccessLogTomlConfig {
+    // A file to append to, or "-" for stdout
+    path: String,
+    #[serde(default = "default_get_sample_rate")]
+    get_sample_rate: f64,
+}
+
+fn default_get_sample_rate() -> f64 {
+    1.0
+}
+
+impl TryFrom<AccessLogTomlConfig> for AccessLog {
+    type Error = io::Error;
+
+    fn try_from(other: AccessLogTomlConfig) -> Result<Self, Self::Error> {
+        if !(0.0..=1.0).contains(&other.get_sample_rate) {
+            return Err(invalid_data(format!(
+                "Access log sample rate must be between 0 and 1: {}",
+                other.get_sample_rate
+            )));
+        }
+        let destination = match other.path.as_str() {
+            "-" => AccessLogDestination::Stdout,
+            path => AccessLogDestination::File(path.into()),
+        };
+        AccessLog::open(&destination, other.get_sample_rate)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ForwardingTomlConfig {
+    #[serde(default = "default_trusted_proxies")]
+    trusted_proxies: Vec<String>,
+    #[serde(default)]
+    emit_forwarded_header: bool,
+}
+
+impl Default for ForwardingTomlConfig {
+    fn default() -> Self {
+        ForwardingTomlConfig {
+            trusted_proxies: default_trusted_proxies(),
+            emit_forwarded_header: false,
+        }
+    }
+}
+
+fn default_trusted_proxies() -> Vec<String> {
+    vec!["127.0.0.0/8".to_string(), "::1/128".to_string()]
+}
+
+impl TryFrom<ForwardingTomlConfig> for ForwardingConfig {
+    type Error = io::Error;
+
+    fn try_from(other: ForwardingTomlConfig) -> Result<Self, Self::Error> {
+        let trusted_proxies = other
+            .trusted_proxies
+            .iter()
+            .map(|network| network.parse())
+            .collect::<Result<_, _>>()
+            .map_err(invalid_data)?;
+        Ok(ForwardingConfig {
+            trusted_proxies,
+            emit_forwarded_header: other.emit_forwarded_header,
+        })
+    }
+}
+
 impl TomlConfig {
     fn read<P: AsRef<Path>>(toml_path: P) -> io::Result<TomlConfig> {
         let toml_str = fs::read_to_string(&toml_path).map_err(|e| {
@@ -78,31 +384,286 @@ impl TomlConfig {
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct HealthHooksTomlConfig {
+    #[serde(default = "default_hook_debounce", with = "humantime_serde")]
+    debounce: Duration,
+    #[serde(default)]
+    commands: Vec<Vec<String>>,
+    #[serde(default)]
+    webhooks: Vec<String>,
+    #[serde(default = "default_hook_timeout", with = "humantime_serde")]
+    timeout: Duration,
+}
+
+impl Default for HealthHooksTomlConfig {
+    fn default() -> Self {
+        HealthHooksTomlConfig {
+            debounce: default_hook_debounce(),
+            commands: Vec::new(),
+            webhooks: Vec::new(),
+            timeout: default_hook_timeout(),
+        }
+    }
+}
+
+fn default_hook_debounce() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_hook_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl TryFrom<HealthHooksTomlConfig> for HealthHooksConfig {
+    type Error = io::Error;
+
+    fn try_from(other: HealthHooksTomlConfig) -> Result<Self, Self::Error> {
+        if other.commands.iter().any(|command| command.is_empty()) {
+            return Err(invalid_data("Health hook commands must not be empty"));
+        }
+        let webhooks: Vec<Uri> = other
+            .webhooks
+            .iter()
+            .map(|webhook| webhook.parse())
+            .collect::<Result<_, _>>()
+            .map_err(invalid_data)?;
+        // Only https:// webhooks need the system's CA certificates, which
+        // some hosts do not have
+        let tls_client_config = if webhooks
+            .iter()
+            .any(|webhook| webhook.scheme() == Some(&Scheme::HTTPS))
+        {
+            BackendTlsConfig::default().client_config()?
+        } else {
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(RootCertStore::empty())
+                .with_no_client_auth()
+        };
+        Ok(HealthHooksConfig {
+            debounce: other.debounce,
+            commands: other.commands,
+            webhooks,
+            timeout: other.timeout,
+            tls_client_config,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum PlacementPolicyTomlConfig {
+    Explicit,
+    ConsistentHash,
+    LeastLoaded,
+    WeightedRandom,
+    Pinned,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlacementTomlConfig {
+    #[serde(default = "default_placement_policy")]
+    policy: PlacementPolicyTomlConfig,
+    // For the pinned policy: where unpinned realms go, and each pinned
+    // realm's backend, as written in `addresses`
+    #[serde(default = "default_placement_fallback")]
+    fallback: PlacementPolicyTomlConfig,
+    #[serde(default)]
+    pins: HashMap<String, String>,
+    #[serde(default)]
+    keep_realms_together: bool,
+    #[serde(default = "default_realm_field")]
+    realm_field: String,
+}
+
+impl Default for PlacementTomlConfig {
+    fn default() -> Self {
+        PlacementTomlConfig {
+            policy: default_placement_policy(),
+            fallback: default_placement_fallback(),
+            pins: HashMap::new(),
+            keep_realms_together: false,
+            realm_field: default_realm_field(),
+        }
+    }
+}
+
+fn default_placement_policy() -> PlacementPolicyTomlConfig {
+    PlacementPolicyTomlConfig::LeastLoaded
+}
+
+fn default_placement_fallback() -> PlacementPolicyTomlConfig {
+    PlacementPolicyTomlConfig::LeastLoaded
+}
+
+fn default_realm_field() -> String {
+    "realm_id".to_string()
+}
+
+impl PlacementTomlConfig {
+    // Pins are checked against the backends they are for
+    fn into_config(self, pool: &BackendPool) -> Result<PlacementConfig, io::Error> {
+        let policy: Box<dyn PlacementPolicy> = match self.policy {
+            PlacementPolicyTomlConfig::Pinned => {
+                if self.fallback == PlacementPolicyTomlConfig::Pinned {
+                    return Err(invalid_data("The pinned policy cannot fall back to itself"));
+                }
+                let mut pins = HashMap::new();
+                for (realm, address) in self.pins {
+                    let uri: Uri = address.parse().map_err(invalid_data)?;
+                    let backend = uri
+                        .authority()
+                        .and_then(|authority| pool.addresses.get(authority.as_str()))
+                        .ok_or_else(|| {
+                            invalid_data(format!(
+                                "Realm {} pinned to unknown backend: {}",
+                                realm, address
+                            ))
+                        })?;
+                    pins.insert(realm, backend.port);
+                }
+                Box::new(Pinned {
+                    pins,
+                    fallback: self.fallback.into(),
+                })
+            }
+            policy => policy.into(),
+        };
+        Ok(PlacementConfig {
+            policy,
+            keep_realms_together: self.keep_realms_together,
+            realm_field: self.realm_field,
+        })
+    }
+}
+
+impl From<PlacementPolicyTomlConfig> for Box<dyn PlacementPolicy> {
+    fn from(other: PlacementPolicyTomlConfig) -> Self {
+        match other {
+            PlacementPolicyTomlConfig::Explicit => Box::new(ExplicitHeader),
+            PlacementPolicyTomlConfig::ConsistentHash => Box::new(ConsistentHash),
+            PlacementPolicyTomlConfig::LeastLoaded => Box::new(LeastLoaded),
+            PlacementPolicyTomlConfig::WeightedRandom => Box::new(WeightedRandom),
+            // Only reached as a fallback, which is checked for
+            PlacementPolicyTomlConfig::Pinned => unreachable!("pinned needs its pins"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum QueueStoreKindTomlConfig {
+    #[default]
+    Memory,
+    Journal,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct QueueStoreTomlConfig {
+    #[serde(default)]
+    kind: QueueStoreKindTomlConfig,
+    // The journal file, for the journal store
+    path: Option<String>,
+}
+
+impl TryFrom<QueueStoreTomlConfig> for Box<dyn QueueStore> {
+    type Error = io::Error;
+
+    fn try_from(other: QueueStoreTomlConfig) -> Result<Self, Self::Error> {
+        Ok(match other.kind {
+            QueueStoreKindTomlConfig::Memory => Box::<MemoryQueueStore>::default(),
+            QueueStoreKindTomlConfig::Journal => {
+                let path = other
+                    .path
+                    .ok_or_else(|| invalid_data("The journal queue store needs a path"))?;
+                Box::new(JournalQueueStore::open(path)?)
+            }
+        })
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct BackendPoolConfig {
     addresses: Vec<String>,
     client: Option<BackendConnectionConfig>,
     #[serde(default = "default_health_config")]
     health_config: HealthTomlConfig,
+    // Keyed by the backend, as written in `addresses`
+    #[serde(default)]
+    health_overrides: HashMap<String, HealthOverrideTomlConfig>,
+    #[serde(default)]
+    circuit_breaker: CircuitBreakerTomlConfig,
+    // Keyed by the backend, as written in `addresses`
+    #[serde(default)]
+    weights: HashMap<String, u32>,
+    #[serde(default)]
+    tls: BackendTlsTomlConfig,
 }
 
-impl From<BackendPoolConfig> for BackendPool {
-    fn from(other: BackendPoolConfig) -> Self {
-        // TODO: This conversion can fail, should we use TryFrom or wrap this in some kind of error?
-        let addresses = other
+impl BackendPoolConfig {
+    // The weight of each backend, by the address it is written as
+    fn take_weights(&mut self) -> Result<HashMap<String, u32>, io::Error> {
+        let mut weights = std::mem::take(&mut self.weights);
+        let resolved = self
             .addresses
-            .into_iter()
-            .map(|address| (address, ArcSwap::from_pointee(Healthiness::Healthy)))
+            .iter()
+            .map(|address| {
+                let weight = weights.remove(address).unwrap_or(DEFAULT_WEIGHT);
+                (address.clone(), weight)
+            })
             .collect();
-        let health_toml_config = other.health_config;
+        if let Some(address) = weights.keys().next() {
+            return Err(invalid_data(format!(
+                "Weight given for unknown backend: {}",
+                address
+            )));
+        }
+        Ok(resolved)
+    }
+}
 
-        let health_config = HealthConfig {
-            timeout: health_toml_config.timeout,
-            interval: health_toml_config.interval,
-            path: health_toml_config.path,
-        };
+impl TryFrom<BackendPoolConfig> for BackendPool {
+    type Error = io::Error;
+
+    fn try_from(mut other: BackendPoolConfig) -> Result<Self, Self::Error> {
+        let weights = other.take_weights()?;
+        let circuit_breaker_config = CircuitBreakerConfig::try_from(other.circuit_breaker)?;
+        let mut health_overrides = other.health_overrides;
+        let mut addresses = HashMap::new();
+        for address in other.addresses {
+            let health_config = match health_overrides.remove(&address) {
+                Some(health_override) => other.health_config.with_override(health_override),
+                None => other.health_config.clone(),
+            };
+            let health_config = HealthConfig::try_from(health_config)?;
+            let (authority, backend) = parse_backend_address(
+                &address,
+                health_config,
+                circuit_breaker_config.clone(),
+                weights[&address],
+            )?;
+            if addresses
+                .values()
+                .any(|existing: &Backend| existing.port == backend.port)
+            {
+                return Err(invalid_data(format!(
+                    "Backend ports must be unique: {}",
+                    address
+                )));
+            }
+            addresses.insert(authority, backend);
+        }
+        if let Some(address) = health_overrides.keys().next() {
+            return Err(invalid_data(format!(
+                "Health check override for unknown backend: {}",
+                address
+            )));
+        }
 
-        let mut builder = BackendPoolBuilder::new(addresses, health_config);
+        let tls_client_config = BackendTlsConfig::from(other.tls).client_config()?;
+
+        let mut builder = BackendPoolBuilder::new(addresses, tls_client_config);
         if let Some(client) = other.client {
             if let Some(pool_idle_timeout) = client.pool_idle_timeout {
                 builder.pool_idle_timeout(pool_idle_timeout);
@@ -111,9 +672,138 @@ impl From<BackendPoolConfig> for BackendPool {
             if let Some(pool_max_idle_per_host) = client.pool_max_idle_per_host {
                 builder.pool_max_idle_per_host(pool_max_idle_per_host);
             }
+
+            if let Some(http2_prior_knowledge) = client.http2_prior_knowledge {
+                builder.http2_prior_knowledge(http2_prior_knowledge);
+            }
         }
 
-        builder.build()
+        Ok(builder.build())
+    }
+}
+
+// Backends are given as `host:port`, or `https://host:port` to connect
+// with TLS.  Certificates are verified against the host, so TLS
+// backends should be named by hostname rather than IP address.
+fn parse_backend_address(
+    address: &str,
+    health_config: HealthConfig,
+    circuit_breaker_config: CircuitBreakerConfig,
+    weight: u32,
+) -> Result<(String, Backend), io::Error> {
+    let uri: Uri = address.parse().map_err(invalid_data)?;
+    let scheme = match uri.scheme() {
+        None => Scheme::HTTP,
+        Some(scheme) if *scheme == Scheme::HTTP || *scheme == Scheme::HTTPS => scheme.clone(),
+        Some(scheme) => {
+            return Err(invalid_data(format!(
+                "Unsupported backend scheme {}: {}",
+                scheme, address
+            )))
+        }
+    };
+    let authority = uri
+        .authority()
+        .ok_or_else(|| invalid_data(format!("Missing backend host: {}", address)))?;
+    let port = authority
+        .port_u16()
+        .ok_or_else(|| invalid_data(format!("Missing backend port: {}", address)))?;
+    Ok((
+        authority.to_string(),
+        Backend::new(scheme, port, health_config, circuit_breaker_config, weight),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct CircuitBreakerTomlConfig {
+    #[serde(default = "default_circuit_window", with = "humantime_serde")]
+    window: Duration,
+    #[serde(default = "default_circuit_min_requests")]
+    min_requests: u32,
+    #[serde(default = "default_circuit_error_rate")]
+    error_rate: f64,
+    #[serde(default = "default_circuit_open_duration", with = "humantime_serde")]
+    open_duration: Duration,
+    #[serde(default = "default_circuit_half_open_requests")]
+    half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerTomlConfig {
+    fn default() -> Self {
+        CircuitBreakerTomlConfig {
+            window: default_circuit_window(),
+            min_requests: default_circuit_min_requests(),
+            error_rate: default_circuit_error_rate(),
+            open_duration: default_circuit_open_duration(),
+            half_open_requests: default_circuit_half_open_requests(),
+        }
+    }
+}
+
+fn default_circuit_window() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_circuit_min_requests() -> u32 {
+    20
+}
+
+fn default_circuit_error_rate() -> f64 {
+    0.5
+}
+
+fn default_circuit_open_duration() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_circuit_half_open_requests() -> u32 {
+    3
+}
+
+impl TryFrom<CircuitBreakerTomlConfig> for CircuitBreakerConfig {
+    type Error = io::Error;
+
+    fn try_from(other: CircuitBreakerTomlConfig) -> Result<Self, Self::Error> {
+        // With no error rate, even a window of successes would open it
+        if !(other.error_rate > 0.0 && other.error_rate <= 1.0) {
+            return Err(invalid_data(format!(
+                "Circuit breaker error rate must be above 0 and at most 1: {}",
+                other.error_rate
+            )));
+        }
+        if other.window.is_zero()
+            || other.open_duration.is_zero()
+            || other.min_requests == 0
+            || other.half_open_requests == 0
+        {
+            return Err(invalid_data(
+                "Circuit breaker window, open_duration, min_requests and half_open_requests must be non-zero",
+            ));
+        }
+        Ok(CircuitBreakerConfig {
+            window: other.window,
+            min_requests: other.min_requests,
+            error_rate: other.error_rate,
+            open_duration: other.open_duration,
+            half_open_requests: other.half_open_requests,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct BackendTlsTomlConfig {
+    ca_bundle: Option<String>,
+    client_certificate: Option<String>,
+    client_key: Option<String>,
+}
+
+impl From<BackendTlsTomlConfig> for BackendTlsConfig {
+    fn from(other: BackendTlsTomlConfig) -> Self {
+        BackendTlsConfig {
+            ca_bundle_path: other.ca_bundle.map(PathBuf::from),
+            client_certificate_path: other.client_certificate.map(PathBuf::from),
+            client_key_path: other.client_key.map(PathBuf::from),
+        }
     }
 }
 
@@ -121,9 +811,10 @@ impl From<BackendPoolConfig> for BackendPool {
 struct BackendConnectionConfig {
     pool_idle_timeout: Option<Duration>,
     pool_max_idle_per_host: Option<usize>,
+    http2_prior_knowledge: Option<bool>,
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Default)]
+#[derive(Debug, Deserialize, PartialEq, Eq, Default, Clone)]
 pub struct HealthTomlConfig {
     #[serde(default = "default_timeout", with = "humantime_serde")]
     pub timeout: Duration,
@@ -131,6 +822,113 @@ pub struct HealthTomlConfig {
     pub interval: Duration,
     #[serde(default = "default_path")]
     pub path: String,
+    #[serde(default = "default_threshold")]
+    pub rise: u32,
+    #[serde(default = "default_threshold")]
+    pub fall: u32,
+    #[serde(default, with = "humantime_serde")]
+    pub healthy_hold_down: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub unhealthy_hold_down: Option<Duration>,
+    // Statuses which count as healthy; by default, any 2xx
+    #[serde(default)]
+    pub expected_statuses: Vec<u16>,
+    pub body_contains: Option<String>,
+    // Sent with every probe, e.g. to authenticate to the backend
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // A probe whose response contains this means the backend is shutting
+    // down: it keeps its existing queues but takes no new ones
+    pub draining_marker: Option<String>,
+    // Where the backend reports its queue count, handlers and memory
+    pub load_path: Option<String>,
+}
+
+impl HealthTomlConfig {
+    fn with_override(&self, other: HealthOverrideTomlConfig) -> HealthTomlConfig {
+        let mut headers = self.headers.clone();
+        headers.extend(other.headers);
+        HealthTomlConfig {
+            timeout: other.timeout.unwrap_or(self.timeout),
+            interval: other.interval.unwrap_or(self.interval),
+            path: other.path.unwrap_or_else(|| self.path.clone()),
+            rise: other.rise.unwrap_or(self.rise),
+            fall: other.fall.unwrap_or(self.fall),
+            healthy_hold_down: other.healthy_hold_down.or(self.healthy_hold_down),
+            unhealthy_hold_down: other.unhealthy_hold_down.or(self.unhealthy_hold_down),
+            expected_statuses: other
+                .expected_statuses
+                .unwrap_or_else(|| self.expected_statuses.clone()),
+            body_contains: other.body_contains.or_else(|| self.body_contains.clone()),
+            headers,
+            draining_marker: other
+                .draining_marker
+                .or_else(|| self.draining_marker.clone()),
+            load_path: other.load_path.or_else(|| self.load_path.clone()),
+        }
+    }
+}
+
+impl TryFrom<HealthTomlConfig> for HealthConfig {
+    type Error = io::Error;
+
+    fn try_from(other: HealthTomlConfig) -> Result<Self, Self::Error> {
+        if other.rise == 0 || other.fall == 0 {
+            return Err(invalid_data(
+                "Health check rise and fall must be at least 1",
+            ));
+        }
+        let expected_statuses = other
+            .expected_statuses
+            .into_iter()
+            .map(StatusCode::from_u16)
+            .collect::<Result<_, _>>()
+            .map_err(invalid_data)?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in other.headers {
+            headers.insert(
+                HeaderName::try_from(name).map_err(invalid_data)?,
+                HeaderValue::try_from(value).map_err(invalid_data)?,
+            );
+        }
+
+        Ok(HealthConfig {
+            timeout: other.timeout,
+            interval: other.interval,
+            path: other.path,
+            rise: other.rise,
+            fall: other.fall,
+            healthy_hold_down: other.healthy_hold_down,
+            unhealthy_hold_down: other.unhealthy_hold_down,
+            expected_statuses,
+            body_contains: other.body_contains,
+            headers,
+            draining_marker: other.draining_marker,
+            load_path: other.load_path,
+        })
+    }
+}
+
+// Any setting of the pool's `health_config`, for one backend
+#[derive(Debug, Deserialize, Default)]
+struct HealthOverrideTomlConfig {
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+    path: Option<String>,
+    rise: Option<u32>,
+    fall: Option<u32>,
+    #[serde(default, with = "humantime_serde")]
+    healthy_hold_down: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    unhealthy_hold_down: Option<Duration>,
+    expected_statuses: Option<Vec<u16>>,
+    body_contains: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    draining_marker: Option<String>,
+    load_path: Option<String>,
 }
 
 fn default_health_config() -> HealthTomlConfig {
@@ -138,6 +936,15 @@ fn default_health_config() -> HealthTomlConfig {
         timeout: default_timeout(),
         interval: default_interval(),
         path: default_path(),
+        rise: default_threshold(),
+        fall: default_threshold(),
+        healthy_hold_down: None,
+        unhealthy_hold_down: None,
+        expected_statuses: Vec::new(),
+        body_contains: None,
+        headers: HashMap::new(),
+        draining_marker: None,
+        load_path: None,
     }
 }
 
@@ -152,3 +959,7 @@ fn default_interval() -> Duration {
 fn default_path() -> String {
     "/".to_string()
 }
+
+fn default_threshold() -> u32 {
+    1
+}
@@ -24,6 +24,6 @@ pub fn bad_gateway() -> Response<Body> {
         .unwrap()
 }
 
-pub fn log_error<E: Error>(error: E) {
-    error!("{}", error);
+pub fn log_error<E: Error>(request_id: &str, error: E) {
+    error!("[{}] {}", request_id, error);
 }
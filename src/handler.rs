@@ -1,31 +1,43 @@
 use crate::{
+    access_log::AccessLogEntry,
+    circuit_breaker::{CircuitBreaker, CircuitBreakerConfig},
     configuration::RuntimeConfig,
     error_response::{bad_gateway, bad_queue, log_error},
-    health::{update_health, HealthConfig, Healthiness},
-    metrics,
-    state::{choose_backend, store_backend, BadBackendError},
+    headers::{remove_hop_by_hop_headers, set_forwarding_headers, ForwardingConfig},
+    health::{HealthConfig, HealthHistory, Healthiness, ShardLoad},
+    metrics::{self, GuardedGauge},
+    state::{choose_backend, request_route, store_backend, BadBackendError},
 };
-use arc_swap::ArcSwap;
-use dashmap::DashMap;
-use futures::Future;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use futures::{Future, TryFutureExt};
 use hyper::{
-    client::HttpConnector, header::HeaderValue, service::Service, Body, Client, Request, Response,
-    StatusCode, Uri,
+    client::HttpConnector, header::HeaderValue, http::uri::Scheme, service::Service, Body, Client,
+    Request, Response, StatusCode, Uri, Version,
 };
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use log::info;
+use opentelemetry::global;
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use rustls::ClientConfig;
+use serde::{Serialize, Serializer};
 use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant},
 };
+use tracing::{info_span, instrument, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
 pub struct MainService {
     pub client_address: SocketAddr,
+    pub secure: bool,
     pub config: Arc<RuntimeConfig>,
-    pub queue_map: Arc<DashMap<String, u16>>,
 }
 
 impl Service<Request<Body>> for MainService {
@@ -39,113 +51,229 @@ impl Service<Request<Body>> for MainService {
     }
 
     fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        let request_id = request_id(&request);
+        request
+            .headers_mut()
+            .insert(X_REQUEST_ID, request_id.clone());
+        let log_id = request_id.to_str().unwrap_or("-").to_string();
+
         info!(
-            "{:#?} {} {}",
+            "[{}] {:#?} {} {}",
+            log_id,
             request.version(),
             request.method(),
             request.uri()
         );
 
-        if request.uri().path() == "/metrics" {
-            return Box::pin(async move { metrics::handler() });
-        }
-
         let config = Arc::clone(&self.config);
 
-        let queue_map = Arc::clone(&self.queue_map);
         let client_address = self.client_address;
+        let proto = if self.secure { "https" } else { "http" };
 
-        Box::pin(metrics::instrumented(
-            request.method().clone(),
-            async move {
-                let pool = &config.backend;
-                let method = request.method().clone();
-                let backend = choose_backend(pool, &queue_map, &mut request).await;
-                match backend {
-                    Ok((port, chosen_backend)) => {
-                        if request.method() == "GET" {
-                            Ok(redirect_to_backend(port, request))
-                        } else {
-                            let resp = forward_request_to_backend(
-                                &chosen_backend,
-                                request,
-                                &client_address,
-                                pool,
-                            )
-                            .await;
-                            store_backend(&queue_map, method, &resp, port);
-                            Ok(resp)
-                        }
-                    }
-                    Err(BadBackendError::UnknownQueue(q)) => Ok(bad_queue(q)),
-                    Err(error) => {
-                        log_error(error);
-                        Ok(bad_gateway())
+        let span = info_span!(
+            "request",
+            method = %request.method(),
+            uri = %request.uri(),
+            client = %client_address,
+            request_id = %log_id,
+        );
+        span.set_parent(global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        }));
+
+        let mut entry = AccessLogEntry::new(
+            &request,
+            &log_id,
+            config
+                .forwarding
+                .client_ip(client_ip(&client_address), request.headers()),
+        );
+
+        let http2 = request.version() == Version::HTTP_2;
+        let future = metrics::instrumented(request.method().clone(), async move {
+            let _stream = http2.then(|| metrics::http2_stream("listener"));
+            let pool = &config.backend;
+            let method = request.method().clone();
+            let route = request_route(&request);
+            let backend =
+                choose_backend(pool, &config.placement, &*config.queue_store, &mut request).await;
+            if let Err(ref error) = backend {
+                metrics::ROUTING_ERRORS
+                    .with_label_values(&[error.kind(), route])
+                    .inc();
+                entry.error = Some(error.kind());
+            }
+            let response = match backend {
+                Ok((port, chosen_backend)) => {
+                    metrics::BACKEND_REQUESTS
+                        .with_label_values(&[&chosen_backend, method.as_str()])
+                        .inc();
+                    entry.backend = Some(chosen_backend.clone());
+                    if request.method() == "GET" {
+                        entry.action = Some(RouteAction::Redirect);
+                        redirect_to_backend(port, request)
+                    } else {
+                        entry.action = Some(RouteAction::Forward);
+                        let resp = forward_request_to_backend(
+                            &chosen_backend,
+                            request,
+                            &client_address,
+                            proto,
+                            &config.forwarding,
+                            pool,
+                            &log_id,
+                        )
+                        .await;
+                        store_backend(&*config.queue_store, method, &resp, port);
+                        resp
                     }
                 }
-            },
-        ))
+                Err(BadBackendError::UnknownQueue(q)) => {
+                    entry.queue_id = Some(q.clone());
+                    bad_queue(q)
+                }
+                Err(error) => {
+                    log_error(&log_id, error);
+                    bad_gateway()
+                }
+            };
+            if let Some(access_log) = &config.access_log {
+                entry.finish(&response);
+                access_log.record(&entry);
+            }
+            Ok((response, entry.action))
+        })
+        .map_ok(|mut response| {
+            response.headers_mut().insert(X_REQUEST_ID, request_id);
+            response
+        });
+        Box::pin(future.instrument(span))
     }
 }
 
-fn append_forwarded_for(existing_forwarded_for: Option<&HeaderValue>, client_ip: String) -> String {
-    match existing_forwarded_for {
-        Some(existing_forwarded_for) => {
-            let forwarded_for = existing_forwarded_for.to_str().unwrap_or("");
-            format!("{}, {}", forwarded_for, client_ip)
+// What was done with a request once a backend was chosen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteAction {
+    Redirect,
+    Forward,
+}
+
+impl RouteAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteAction::Redirect => "redirect",
+            RouteAction::Forward => "forward",
         }
-        None => client_ip,
     }
 }
 
+impl Serialize for RouteAction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+// Reuses the request ID assigned by nginx, if there is a sensible one,
+// so that logs can be correlated across nginx, kansas and Tornado.
+fn request_id(request: &Request<Body>) -> HeaderValue {
+    match request.headers().get(X_REQUEST_ID) {
+        Some(existing)
+            if !existing.is_empty() && existing.len() <= 200 && existing.to_str().is_ok() =>
+        {
+            existing.clone()
+        }
+        _ => HeaderValue::from_str(&format!("{:032x}", rand::random::<u128>())).unwrap(),
+    }
+}
+
+// IPv4 addresses may show as as their IPv4-in-IPv6 equivalent, like `::ffff:127.0.0.1`
+pub fn client_ip(client_address: &SocketAddr) -> IpAddr {
+    match client_address.ip() {
+        IpAddr::V4(v4) => IpAddr::V4(v4),
+        IpAddr::V6(v6) => v6.to_ipv4().map_or(IpAddr::V6(v6), IpAddr::V4),
+    }
+}
+
+#[instrument(skip(request, client_address, forwarding, pool, request_id))]
 async fn forward_request_to_backend(
     backend_address: &str,
     request: Request<Body>,
     client_address: &SocketAddr,
+    proto: &'static str,
+    forwarding: &ForwardingConfig,
     pool: &BackendPool,
+    request_id: &str,
 ) -> Response<Body> {
+    let backend = pool.addresses.get(backend_address).unwrap();
     let path = request.uri().path_and_query().unwrap().clone();
     let url = Uri::builder()
-        .scheme("http")
+        .scheme(backend.scheme.clone())
         .authority(backend_address)
         .path_and_query(path)
         .build()
         .unwrap();
 
-    let builder = Request::builder().uri(url);
+    let (parts, body) = request.into_parts();
+    let mut headers = parts.headers;
+    // Our own request ID goes through, even if the client named it in
+    // `Connection`
+    let request_id_header = headers.get(X_REQUEST_ID).cloned();
+    remove_hop_by_hop_headers(&mut headers);
+    if let Some(value) = request_id_header {
+        headers.insert(X_REQUEST_ID, value);
+    }
+    set_forwarding_headers(&mut headers, client_ip(client_address), proto, forwarding);
 
-    // IPv4 addresses may show as as their IPv4-in-IPv6 equivalent, like `::ffff:127.0.0.1`
-    let ip = match client_address.ip() {
-        IpAddr::V4(v4) => v4.to_string(),
-        IpAddr::V6(v6) => v6
-            .to_ipv4()
-            .map_or_else(|| v6.to_string(), |v4| v4.to_string()),
-    };
-    let builder = request
-        .headers()
-        .iter()
-        .fold(builder, |builder, (key, val)| builder.header(key, val))
-        .header(
-            "x-forwarded-for",
-            append_forwarded_for(request.headers().get("x-forwarded-for"), ip),
-        )
-        .method(request.method());
+    let mut backend_request = Request::builder()
+        .method(parts.method)
+        .uri(url)
+        .body(body)
+        .unwrap();
+    *backend_request.headers_mut() = headers;
 
-    let backend_request = builder.body(request.into_body()).unwrap();
+    // Propagate our span to Tornado, as a W3C `traceparent` header
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(
+            &Span::current().context(),
+            &mut HeaderInjector(backend_request.headers_mut()),
+        )
+    });
 
+    let _guard = metrics::BACKEND_IN_FLIGHT
+        .with_label_values(&[backend_address])
+        .guarded_inc();
+    let _stream = pool
+        .http2_prior_knowledge
+        .then(|| metrics::http2_stream("backend"));
+    let now = Instant::now();
     let result = pool.client.request(backend_request).await;
 
-    // Update the backend state
-    let healthiness = pool.addresses.get(backend_address).unwrap();
-    update_health(backend_address, &result, healthiness, false);
+    // Client errors say nothing about the backend
+    let success = match &result {
+        Err(_) => false,
+        Ok(response) => !response.status().is_server_error(),
+    };
+    backend.circuit_breaker.record(backend_address, success);
 
     // 502 on errors
     match result {
         Err(error) => {
-            log_error(error);
+            metrics::BACKEND_ERRORS
+                .with_label_values(&[backend_address])
+                .inc();
+            log_error(request_id, error);
             bad_gateway()
         }
-        Ok(res) => res,
+        Ok(mut res) => {
+            remove_hop_by_hop_headers(res.headers_mut());
+            let status = res.status().as_u16().to_string();
+            metrics::BACKEND_RESPONSE_TIME
+                .with_label_values(&[backend_address, status.as_str()])
+                .observe(now.elapsed().as_secs_f64());
+            res
+        }
     }
 }
 
@@ -162,30 +290,99 @@ fn redirect_to_backend(port: u16, request: Request<Body>) -> Response<Body> {
         .unwrap()
 }
 
+// The weight of a backend whose weight is not configured; weights are
+// relative, so this leaves room to lower one gradually.
+pub const DEFAULT_WEIGHT: u32 = 100;
+
 #[derive(Debug)]
-pub struct BackendPool {
-    pub addresses: HashMap<String, ArcSwap<Healthiness>>,
+pub struct Backend {
+    pub scheme: Scheme,
+    pub port: u16,
     pub health_config: HealthConfig,
-    pub client: Client<HttpConnector, Body>,
+    // How many new queues the backend takes relative to the others; 0
+    // takes none.  May be changed at runtime.
+    weight: AtomicU32,
+    pub healthiness: ArcSwap<Healthiness>,
+    pub load: ArcSwapOption<ShardLoad>,
+    pub history: Mutex<HealthHistory>,
+    pub circuit_breaker: CircuitBreaker,
+}
+
+impl Backend {
+    pub fn new(
+        scheme: Scheme,
+        port: u16,
+        health_config: HealthConfig,
+        circuit_breaker_config: CircuitBreakerConfig,
+        weight: u32,
+    ) -> Backend {
+        Backend {
+            scheme,
+            port,
+            health_config,
+            weight: AtomicU32::new(weight),
+            healthiness: ArcSwap::from_pointee(Healthiness::Healthy),
+            load: ArcSwapOption::empty(),
+            history: Mutex::new(HealthHistory::default()),
+            circuit_breaker: CircuitBreaker::new(circuit_breaker_config),
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    pub fn set_weight(&self, address: &str, weight: u32) {
+        let previous = self.weight.swap(weight, Ordering::Relaxed);
+        if previous != weight {
+            info!(
+                "Weight of backend {} changed from {} to {}",
+                address, previous, weight
+            );
+            metrics::BACKEND_WEIGHT
+                .with_label_values(&[address])
+                .set(weight.into());
+        }
+    }
+}
+
+pub struct BackendPool {
+    pub addresses: HashMap<String, Backend>,
+    pub client: Client<HttpsConnector<HttpConnector>, Body>,
+    pub health_client: Client<HttpsConnector<HttpConnector>, Body>,
+    pub http2_prior_knowledge: bool,
+}
+
+impl BackendPool {
+    // Queues are assigned to backends by port, which is also how nginx
+    // addresses them in `X-Accel-Redirect`, so ports must be unique
+    // across the pool.
+    pub fn backend_for_port(&self, port: u16) -> Option<(&String, &Backend)> {
+        self.addresses
+            .iter()
+            .find(|(_, backend)| backend.port == port)
+    }
 }
 
 pub struct BackendPoolBuilder {
-    addresses: HashMap<String, ArcSwap<Healthiness>>,
-    health_config: HealthConfig,
+    addresses: HashMap<String, Backend>,
+    tls_client_config: ClientConfig,
     pool_idle_timeout: Option<Duration>,
     pool_max_idle_per_host: Option<usize>,
+    http2_prior_knowledge: bool,
 }
 
 impl BackendPoolBuilder {
     pub fn new(
-        addresses: HashMap<String, ArcSwap<Healthiness>>,
-        health_config: HealthConfig,
+        addresses: HashMap<String, Backend>,
+        tls_client_config: ClientConfig,
     ) -> BackendPoolBuilder {
         BackendPoolBuilder {
             addresses,
-            health_config,
+            tls_client_config,
             pool_idle_timeout: None,
             pool_max_idle_per_host: None,
+            http2_prior_knowledge: false,
         }
     }
 
@@ -199,6 +396,14 @@ impl BackendPoolBuilder {
         self
     }
 
+    // Speak HTTP/2 to the backends without negotiating it first: h2c for
+    // plain backends, and h2 via ALPN for TLS ones.  Forwards are then
+    // multiplexed over a single connection to each backend.
+    pub fn http2_prior_knowledge(&mut self, enabled: bool) -> &BackendPoolBuilder {
+        self.http2_prior_knowledge = enabled;
+        self
+    }
+
     pub fn build(self) -> BackendPool {
         let mut client_builder = Client::builder();
         if let Some(pool_idle_timeout) = self.pool_idle_timeout {
@@ -207,13 +412,33 @@ impl BackendPoolBuilder {
         if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
             client_builder.pool_max_idle_per_host(pool_max_idle_per_host);
         }
+        client_builder.http2_only(self.http2_prior_knowledge);
+        let client: Client<_, Body> = client_builder.build(self.connector());
 
-        let client: Client<_, Body> = client_builder.build(HttpConnector::new());
+        // Health checks get a client of their own, so that probes are not
+        // queued behind forwarded requests, but keep their connections
+        // alive between probes like any other client.  Each probe is
+        // bounded by its backend's timeout, connecting included.
+        let health_client: Client<_, Body> = Client::builder()
+            .http2_only(self.http2_prior_knowledge)
+            .build(self.connector());
 
         BackendPool {
             addresses: self.addresses,
-            health_config: self.health_config,
             client,
+            health_client,
+            http2_prior_knowledge: self.http2_prior_knowledge,
+        }
+    }
+
+    fn connector(&self) -> HttpsConnector<HttpConnector> {
+        let connector = HttpsConnectorBuilder::new()
+            .with_tls_config(self.tls_client_config.clone())
+            .https_or_http();
+        if self.http2_prior_knowledge {
+            connector.enable_http2().build()
+        } else {
+            connector.enable_http1().build()
         }
     }
 }
@@ -0,0 +1,143 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, FORWARDED, HOST};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+pub struct ForwardingConfig {
+    pub trusted_proxies: Vec<IpNet>,
+    pub emit_forwarded_header: bool,
+}
+
+impl ForwardingConfig {
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    // The address of the original client: the right-most address in
+    // `X-Forwarded-For` which was not added by one of our trusted
+    // proxies, or the peer itself if it is not a trusted proxy.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarded_for_addresses(headers).into_iter().rev() {
+            client = hop;
+            if !self.is_trusted(&hop) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+fn forwarded_for_addresses(headers: &HeaderMap) -> Vec<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|hop| hop.trim().parse().ok())
+        .collect()
+}
+
+// Headers which describe a single connection, and must not be passed
+// along by a proxy; see RFC 7230 section 6.1.  `Keep-Alive` and
+// `Proxy-Connection` are not standard, but are sent in practice.
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    // Any header named in `Connection` is also hop-by-hop
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+// Rewrites the headers which describe how the request reached us.
+// The client's `Host` moves into `X-Forwarded-Host`, so the backend
+// request is addressed to the backend itself, and the peer is appended
+// to `X-Forwarded-For` (and optionally `Forwarded`).  Values for these
+// which we were sent are only kept if the peer is a trusted proxy.
+pub fn set_forwarding_headers(
+    headers: &mut HeaderMap,
+    peer: IpAddr,
+    proto: &'static str,
+    config: &ForwardingConfig,
+) {
+    let trusted = config.is_trusted(&peer);
+    if !trusted {
+        for name in [
+            "x-forwarded-for",
+            "x-forwarded-host",
+            "x-forwarded-proto",
+            "forwarded",
+        ] {
+            headers.remove(name);
+        }
+    }
+
+    let host = headers.remove(HOST);
+    if config.emit_forwarded_header {
+        let element = forwarded_element(peer, host.as_ref(), proto);
+        let forwarded = appended(headers, FORWARDED.as_str(), element);
+        if let Ok(value) = HeaderValue::from_str(&forwarded) {
+            headers.insert(FORWARDED, value);
+        }
+    }
+    if let Some(host) = host {
+        headers.entry("x-forwarded-host").or_insert(host);
+    }
+    headers
+        .entry("x-forwarded-proto")
+        .or_insert(HeaderValue::from_static(proto));
+
+    let forwarded_for = appended(headers, "x-forwarded-for", peer.to_string());
+    headers.insert(
+        "x-forwarded-for",
+        HeaderValue::from_str(&forwarded_for).unwrap(),
+    );
+}
+
+// Every value we were sent for a list header, on one line, followed by
+// ours; a proxy may have sent the list split across several lines.
+fn appended(headers: &HeaderMap, name: &str, value: String) -> String {
+    let mut values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|existing| existing.to_str().ok())
+        .collect();
+    values.push(&value);
+    values.join(", ")
+}
+
+// One element of an RFC 7239 `Forwarded` header
+fn forwarded_element(peer: IpAddr, host: Option<&HeaderValue>, proto: &str) -> String {
+    let mut element = match peer {
+        IpAddr::V4(v4) => format!("for={}", v4),
+        IpAddr::V6(v6) => format!("for=\"[{}]\"", v6),
+    };
+    if let Some(host) = host.and_then(|h| h.to_str().ok()) {
+        element.push_str(&format!(";host=\"{}\"", host));
+    }
+    element.push_str(&format!(";proto={}", proto));
+    element
+}
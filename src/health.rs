@@ -1,39 +1,91 @@
-use crate::RuntimeConfig;
-use arc_swap::ArcSwap;
+use crate::{
+    handler::{Backend, BackendPool},
+    hooks::{HealthChange, HealthHooks},
+    metrics, RuntimeConfig,
+};
+use bytes::Bytes;
 use futures::future::join_all;
 use hyper::{
     client::HttpConnector,
+    header::HeaderMap,
     http::uri::{self, Authority},
-    Body, Client, Response, Result, StatusCode, Uri,
+    Body, Client, Request, StatusCode,
 };
-use hyper_timeout::TimeoutConnector;
-use log::warn;
+use hyper_rustls::HttpsConnector;
+use log::{debug, warn};
 use serde::Deserialize;
 use std::{
     fmt::{self, Debug},
+    io,
     str::FromStr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::time::interval;
+use tokio::time::sleep;
+
+// Each wait between probes is the interval give or take this fraction
+const PROBE_JITTER: f64 = 0.1;
 
-#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HealthConfig {
     pub timeout: Duration,
     pub interval: Duration,
     pub path: String,
+    // Consecutive successes before an unhealthy backend is healthy again,
+    // and consecutive failures before a healthy one is unhealthy
+    pub rise: u32,
+    pub fall: u32,
+    // Minimum time a backend stays healthy, or unhealthy, before it may
+    // change state again
+    pub healthy_hold_down: Option<Duration>,
+    pub unhealthy_hold_down: Option<Duration>,
+    // Statuses which count as healthy; if empty, any 2xx
+    pub expected_statuses: Vec<StatusCode>,
+    pub body_contains: Option<String>,
+    pub headers: HeaderMap,
+    pub draining_marker: Option<String>,
+    // Where to fetch the shard's `ShardLoad` from, if anywhere
+    pub load_path: Option<String>,
+}
+
+// What a shard reports about how busy it is
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShardLoad {
+    pub queues: u64,
+    #[serde(default)]
+    pub handlers: u64,
+    #[serde(default)]
+    pub memory_bytes: u64,
+}
+
+// The results leading up to the backend's current state
+#[derive(Debug, Default)]
+pub struct HealthHistory {
+    consecutive_successes: u32,
+    consecutive_failures: u32,
+    last_change: Option<Instant>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Healthiness {
     Healthy,
+    // Shutting down: still serving its queues, but not taking new ones
+    Draining,
     Unresponsive(Option<StatusCode>),
 }
 
+impl Healthiness {
+    // Whether requests for existing queues may be sent to the backend
+    pub fn is_up(&self) -> bool {
+        !matches!(self, Healthiness::Unresponsive(_))
+    }
+}
+
 impl fmt::Display for Healthiness {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Healthiness::Healthy => write!(f, "Healthy"),
+            Healthiness::Draining => write!(f, "Draining"),
             Healthiness::Unresponsive(Some(status_code)) => {
                 write!(f, "Unresponsive, status: {}", status_code)
             }
@@ -43,72 +95,219 @@ impl fmt::Display for Healthiness {
 }
 
 pub async fn watch_health(config: &RuntimeConfig) {
-    let mut interval_timer = interval(config.backend.health_config.interval);
+    for (server_address, backend) in config.backend.addresses.iter() {
+        metrics::set_backend_health(server_address, &backend.healthiness.load());
+        metrics::set_circuit_state(server_address, backend.circuit_breaker.state());
+        metrics::BACKEND_WEIGHT
+            .with_label_values(&[server_address])
+            .set(backend.weight().into());
+    }
+
+    let hooks = HealthHooks::start(&config.health_hooks);
+    let checks = config
+        .backend
+        .addresses
+        .iter()
+        .map(|(server_address, backend)| {
+            watch_backend(server_address, backend, &config.backend, &hooks)
+        });
+    join_all(checks).await;
+}
+
+// Probes one backend forever.  Each backend starts at a random point in
+// the interval, and every wait is jittered, so that the probes of a
+// large pool are spread out rather than all arriving at once.
+async fn watch_backend(
+    server_address: &str,
+    backend: &Backend,
+    pool: &BackendPool,
+    hooks: &HealthHooks,
+) {
+    let interval = backend.health_config.interval;
+    sleep(interval.mul_f64(rand::random::<f64>())).await;
     loop {
-        interval_timer.tick().await;
-        let checks = config
-            .backend
-            .addresses
-            .iter()
-            .map(|(server_address, healthiness)| {
-                check_server_health_once(
-                    server_address.clone(),
-                    healthiness,
-                    &config.backend.health_config,
-                )
-            });
-        join_all(checks).await;
+        check_server_health_once(server_address, backend, pool, hooks).await;
+        let jitter = 1.0 + PROBE_JITTER * (2.0 * rand::random::<f64>() - 1.0);
+        sleep(interval.mul_f64(jitter)).await;
     }
 }
 
 /* Contacts one server and sets health value if changed */
 async fn check_server_health_once(
-    server_address: String,
-    healthiness: &ArcSwap<Healthiness>,
-    health_config: &HealthConfig,
+    server_address: &str,
+    backend: &Backend,
+    pool: &BackendPool,
+    hooks: &HealthHooks,
 ) {
+    let health_config = &backend.health_config;
+    let healthiness = &backend.healthiness;
+    let request = probe_request(server_address, backend, &health_config.path);
+
+    let now = Instant::now();
+    let result = contact_server(&pool.health_client, request, health_config.timeout).await;
+    let elapsed = now.elapsed().as_secs_f64();
+
+    // The metrics describe this probe, whatever state the backend is
+    // held in until enough probes agree
+    let probe = probe_result(health_config, result);
+    let state = metrics::health_state_label(&probe);
+    metrics::HEALTH_CHECK_TIME
+        .with_label_values(&[server_address, state])
+        .observe(elapsed);
+    if probe.is_up() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        metrics::HEALTH_CHECK_LAST_SUCCESS
+            .with_label_values(&[server_address])
+            .set(timestamp);
+    }
+    update_health(server_address, probe, backend, hooks);
+
+    if let Some(load_path) = &health_config.load_path {
+        let load = if healthiness.load().is_up() {
+            fetch_load(server_address, backend, pool, load_path).await
+        } else {
+            None
+        };
+        metrics::set_shard_load(server_address, load.as_ref());
+        backend.load.store(load.map(Arc::new));
+    }
+}
+
+fn probe_request(server_address: &str, backend: &Backend, path: &str) -> Request<Body> {
     let uri = uri::Uri::builder()
-        .scheme("http")
-        .path_and_query(&health_config.path)
-        .authority(Authority::from_str(&server_address).unwrap())
+        .scheme(backend.scheme.clone())
+        .path_and_query(path)
+        .authority(Authority::from_str(server_address).unwrap())
         .build()
         .unwrap();
+    let mut request = Request::get(uri).body(Body::empty()).unwrap();
+    *request.headers_mut() = backend.health_config.headers.clone();
+    request
+}
 
-    let result = contact_server(uri, health_config.timeout).await;
-    update_health(&server_address, &result, healthiness, true)
+// Asks the shard how loaded it is.  A shard which cannot say is treated
+// as having no known load, rather than as unhealthy.
+async fn fetch_load(
+    server_address: &str,
+    backend: &Backend,
+    pool: &BackendPool,
+    load_path: &str,
+) -> Option<ShardLoad> {
+    let request = probe_request(server_address, backend, load_path);
+    let result = contact_server(&pool.health_client, request, backend.health_config.timeout)
+        .await
+        .and_then(|(status, body)| {
+            if !status.is_success() {
+                return Err(io::Error::other(format!("status {}", status)));
+            }
+            serde_json::from_slice(&body).map_err(io::Error::from)
+        });
+    match result {
+        Ok(load) => Some(load),
+        Err(e) => {
+            debug!("Could not fetch load of {}: {}", server_address, e);
+            None
+        }
+    }
 }
 
-async fn contact_server(server_address: Uri, timeout: Duration) -> Result<Response<Body>> {
-    let http_connector = HttpConnector::new();
-    let mut connector = TimeoutConnector::new(http_connector);
-    connector.set_connect_timeout(Some(timeout));
-    connector.set_read_timeout(Some(timeout));
-    connector.set_write_timeout(Some(timeout));
-    let client = Client::builder().build::<_, hyper::Body>(connector);
+// The whole probe, including connecting and reading the body, must
+// finish within the timeout.  Reading the body also lets the connection
+// be reused.
+async fn contact_server(
+    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    request: Request<Body>,
+    timeout: Duration,
+) -> io::Result<(StatusCode, Bytes)> {
+    let probe = async {
+        let response = client.request(request).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        Ok::<_, hyper::Error>((status, body))
+    };
+    match tokio::time::timeout(timeout, probe).await {
+        Ok(result) => result.map_err(io::Error::other),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Health check timed out",
+        )),
+    }
+}
 
-    client.get(server_address).await
+fn probe_result(config: &HealthConfig, result: io::Result<(StatusCode, Bytes)>) -> Healthiness {
+    let (status, body) = match result {
+        Err(_) => return Healthiness::Unresponsive(None),
+        Ok(response) => response,
+    };
+    let body = String::from_utf8_lossy(&body);
+    if let Some(marker) = &config.draining_marker {
+        if body.contains(marker.as_str()) {
+            return Healthiness::Draining;
+        }
+    }
+    let expected_status = if config.expected_statuses.is_empty() {
+        status.is_success()
+    } else {
+        config.expected_statuses.contains(&status)
+    };
+    let expected_body = match &config.body_contains {
+        Some(needle) => body.contains(needle.as_str()),
+        None => true,
+    };
+    if expected_status && expected_body {
+        Healthiness::Healthy
+    } else {
+        Healthiness::Unresponsive(Some(status))
+    }
 }
 
-pub fn update_health(
+fn update_health(
     server_address: &str,
-    result: &Result<Response<Body>>,
-    healthiness: &ArcSwap<Healthiness>,
-    strict: bool,
+    result: Healthiness,
+    backend: &Backend,
+    hooks: &HealthHooks,
 ) {
-    let result = match result {
-        Err(_) => Healthiness::Unresponsive(None),
-        Ok(response) => {
-            if response.status().is_success() {
-                Healthiness::Healthy
-            } else if response.status().is_client_error() && !strict {
+    let config = &backend.health_config;
+    // Held while deciding, so that concurrent results are counted in turn
+    let mut history = backend.history.lock().unwrap();
+    let threshold_met = if result.is_up() {
+        history.consecutive_failures = 0;
+        history.consecutive_successes = history.consecutive_successes.saturating_add(1);
+        history.consecutive_successes >= config.rise
+    } else {
+        history.consecutive_successes = 0;
+        history.consecutive_failures = history.consecutive_failures.saturating_add(1);
+        history.consecutive_failures >= config.fall
+    };
+    let current = backend.healthiness.load_full();
+    if !threshold_met || *current == result {
+        return;
+    }
+
+    // Moving between healthy and draining, or changing only the reason
+    // for being unhealthy, is not held down
+    let was_up = current.is_up();
+    if was_up != result.is_up() {
+        let hold_down = if was_up {
+            config.healthy_hold_down
+        } else {
+            config.unhealthy_hold_down
+        };
+        if let (Some(hold_down), Some(last_change)) = (hold_down, history.last_change) {
+            if last_change.elapsed() < hold_down {
                 return;
-            } else {
-                Healthiness::Unresponsive(Some(response.status()))
             }
         }
-    };
-
-    if **healthiness.load() != result && *healthiness.swap(Arc::new(result.clone())) != result {
-        warn!("Backend health change for {}: {}", &server_address, &result);
+        history.last_change = Some(Instant::now());
     }
+
+    backend.healthiness.store(Arc::new(result.clone()));
+    warn!("Backend health change for {}: {}", &server_address, &result);
+    metrics::set_backend_health(server_address, &result);
+    metrics::BACKEND_HEALTH_TRANSITIONS
+        .with_label_values(&[server_address, metrics::health_state_label(&result)])
+        .inc();
+    hooks.notify(HealthChange::new(server_address, &current, &result));
 }
@@ -0,0 +1,192 @@
+use crate::{health::Healthiness, metrics};
+use hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{error, info};
+use rustls::ClientConfig;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    process::Command,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::{sleep_until, timeout, Instant},
+};
+
+#[derive(Clone)]
+pub struct HealthHooksConfig {
+    // Changes are only reported once a backend's state has been settled
+    // for this long, and not at all if it went back to where it was
+    pub debounce: Duration,
+    // Each is a program and its arguments
+    pub commands: Vec<Vec<String>>,
+    pub webhooks: Vec<Uri>,
+    pub timeout: Duration,
+    pub tls_client_config: ClientConfig,
+}
+
+// What the hooks are told about a backend changing state; commands get
+// the same as `KANSAS_*` environment variables.
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthChange {
+    pub backend: String,
+    pub state: &'static str,
+    pub previous_state: &'static str,
+    pub detail: String,
+    pub timestamp: f64,
+}
+
+impl HealthChange {
+    pub fn new(backend: &str, previous: &Healthiness, current: &Healthiness) -> HealthChange {
+        HealthChange {
+            backend: backend.to_string(),
+            state: metrics::health_state_label(current),
+            previous_state: metrics::health_state_label(previous),
+            detail: current.to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0.0, |d| d.as_secs_f64()),
+        }
+    }
+}
+
+// Where health changes are sent; cheap to clone, and never blocks.
+#[derive(Debug, Clone)]
+pub struct HealthHooks {
+    sender: Option<UnboundedSender<HealthChange>>,
+}
+
+impl HealthHooks {
+    // Starts delivering to the configured hooks, if there are any
+    pub fn start(config: &HealthHooksConfig) -> HealthHooks {
+        if config.commands.is_empty() && config.webhooks.is_empty() {
+            return HealthHooks { sender: None };
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(debounce(Arc::new(config.clone()), receiver));
+        HealthHooks {
+            sender: Some(sender),
+        }
+    }
+
+    pub fn notify(&self, change: HealthChange) {
+        if let Some(sender) = &self.sender {
+            // Only fails if the delivery task is gone, at shutdown
+            let _ = sender.send(change);
+        }
+    }
+}
+
+// Holds each backend's changes until it has been quiet for the debounce
+// period, then reports the net change from the state last reported.
+async fn debounce(config: Arc<HealthHooksConfig>, mut changes: UnboundedReceiver<HealthChange>) {
+    let client = HttpsConnectorBuilder::new()
+        .with_tls_config(config.tls_client_config.clone())
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client: Client<_, Body> = Client::builder().build(client);
+    let mut pending: HashMap<String, (HealthChange, Instant)> = HashMap::new();
+
+    loop {
+        let next_deadline = pending.values().map(|(_, deadline)| *deadline).min();
+        tokio::select! {
+            change = changes.recv() => {
+                let change = match change {
+                    Some(change) => change,
+                    None => return,
+                };
+                let deadline = Instant::now() + config.debounce;
+                let previous_state = match pending.remove(&change.backend) {
+                    Some((held, _)) => held.previous_state,
+                    None => change.previous_state,
+                };
+                let change = HealthChange { previous_state, ..change };
+                pending.insert(change.backend.clone(), (change, deadline));
+            }
+            _ = sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                let now = Instant::now();
+                let due: Vec<String> = pending
+                    .iter()
+                    .filter(|(_, (_, deadline))| *deadline <= now)
+                    .map(|(backend, _)| backend.clone())
+                    .collect();
+                for backend in due {
+                    let (change, _) = pending.remove(&backend).unwrap();
+                    if change.state != change.previous_state {
+                        tokio::spawn(fire(Arc::clone(&config), client.clone(), change));
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn fire(
+    config: Arc<HealthHooksConfig>,
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    change: HealthChange,
+) {
+    info!(
+        "Running health hooks for {}: {} -> {}",
+        change.backend, change.previous_state, change.state
+    );
+    for command in &config.commands {
+        if let Err(e) = run_command(command, &change, config.timeout).await {
+            error!("Health hook {:?} failed: {}", command, e);
+        }
+    }
+    for webhook in &config.webhooks {
+        if let Err(e) = post_webhook(&client, webhook, &change, config.timeout).await {
+            error!("Health webhook {} failed: {}", webhook, e);
+        }
+    }
+}
+
+async fn run_command(
+    command: &[String],
+    change: &HealthChange,
+    limit: Duration,
+) -> Result<(), String> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .env("KANSAS_BACKEND", &change.backend)
+        .env("KANSAS_STATE", change.state)
+        .env("KANSAS_PREVIOUS_STATE", change.previous_state)
+        .env("KANSAS_DETAIL", &change.detail)
+        .env("KANSAS_TIMESTAMP", change.timestamp.to_string())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    match timeout(limit, child.wait()).await {
+        Err(_) => Err("timed out".to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Ok(Ok(status)) if !status.success() => Err(status.to_string()),
+        Ok(Ok(_)) => Ok(()),
+    }
+}
+
+async fn post_webhook(
+    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    webhook: &Uri,
+    change: &HealthChange,
+    limit: Duration,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(change).map_err(|e| e.to_string())?;
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(webhook)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap();
+    match timeout(limit, client.request(request)).await {
+        Err(_) => Err("timed out".to_string()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Ok(Ok(response)) if !response.status().is_success() => {
+            Err(format!("status {}", response.status()))
+        }
+        Ok(Ok(_)) => Ok(()),
+    }
+}
@@ -1,15 +1,25 @@
 use clap::{Arg, Command};
-use configuration::{read_initial_config, RuntimeConfig};
+use configuration::{read_initial_config, reload_weights_on_sighup, RuntimeConfig};
 use std::{io, sync::Arc};
 use tokio::try_join;
 
+mod access_log;
+mod admin;
+mod circuit_breaker;
 mod configuration;
 mod error_response;
 mod handler;
+mod headers;
 mod health;
+mod hooks;
 mod metrics;
+mod placement;
+mod proxy_protocol;
+mod queue_store;
 mod server;
 mod state;
+mod telemetry;
+mod tls;
 
 #[macro_use]
 extern crate lazy_static;
@@ -33,12 +43,14 @@ pub async fn main() -> Result<(), io::Error> {
 
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    console_subscriber::init();
-
     let config = Arc::new(read_initial_config(&config_path).await?);
+    metrics::configure(&config.metrics);
+    telemetry::init(&config.tracing).map_err(io::Error::other)?;
     try_join!(
         watch_health(Arc::clone(&config)),
         listen_for_http_request(Arc::clone(&config)),
+        listen_for_admin_request(Arc::clone(&config)),
+        reload_weights_on_sighup(Arc::clone(&config), config_path.into()),
     )?;
     Ok(())
 }
@@ -51,3 +63,7 @@ async fn watch_health(config: Arc<RuntimeConfig>) -> Result<(), io::Error> {
 async fn listen_for_http_request(config: Arc<RuntimeConfig>) -> Result<(), io::Error> {
     server::create(config).await
 }
+
+async fn listen_for_admin_request(config: Arc<RuntimeConfig>) -> Result<(), io::Error> {
+    admin::create(config).await
+}
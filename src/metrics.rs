@@ -1,12 +1,62 @@
 use std::future::Future;
+use std::sync::{Arc, OnceLock};
 use std::time::Instant;
 
 use hyper::{Body, Error, Method, Response, StatusCode};
 use prometheus::{
-    register_histogram_vec, register_int_counter_vec, register_int_gauge, HistogramVec,
-    IntCounterVec, IntGauge, Opts, TextEncoder,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    register_gauge_vec, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, GaugeVec, HistogramVec, IntCounter, IntCounterVec,
+    IntGauge, IntGaugeVec, Opts, TextEncoder,
 };
 
+use crate::{
+    circuit_breaker::CircuitState,
+    configuration::RuntimeConfig,
+    handler::RouteAction,
+    health::{Healthiness, ShardLoad},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsConfig {
+    pub redirect_buckets: Vec<f64>,
+    pub forward_buckets: Vec<f64>,
+    pub backend_buckets: Vec<f64>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            // Only our own time to answer with `X-Accel-Redirect`; the
+            // long-poll itself is held by nginx and Tornado
+            redirect_buckets: vec![
+                0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1,
+            ],
+            forward_buckets: vec![
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ],
+            backend_buckets: vec![
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ],
+        }
+    }
+}
+
+static CONFIG: OnceLock<MetricsConfig> = OnceLock::new();
+
+// Must be called before any of the histograms below are first used,
+// or they will be registered with the default buckets.
+pub fn configure(config: &MetricsConfig) {
+    if CONFIG.set(config.clone()).is_err() {
+        panic!("Metrics were configured twice");
+    }
+}
+
+fn config() -> &'static MetricsConfig {
+    CONFIG.get_or_init(MetricsConfig::default)
+}
+
 lazy_static! {
     pub static ref OPEN_CONNECTIONS: IntGauge =
         register_int_gauge!("kansas_open_connections_total", "Current open connections").unwrap();
@@ -27,35 +77,301 @@ lazy_static! {
         vec![0.0, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0],
     )
     .unwrap();
+    pub static ref ACCESS_LOG_DROPPED: IntCounter = register_int_counter!(
+        "kansas_access_log_dropped_total",
+        "Access log entries dropped because the writer fell behind"
+    )
+    .unwrap();
+    pub static ref REDIRECT_RESPONSE_TIME: HistogramVec = register_histogram_vec!(
+        "kansas_redirect_response_time_seconds",
+        "Response times of long-poll requests redirected to a backend",
+        &["method", "status"],
+        config().redirect_buckets.clone(),
+    )
+    .unwrap();
+    pub static ref FORWARD_RESPONSE_TIME: HistogramVec = register_histogram_vec!(
+        "kansas_forward_response_time_seconds",
+        "Response times of queue creation and deletion requests forwarded to a backend",
+        &["method", "status"],
+        config().forward_buckets.clone(),
+    )
+    .unwrap();
+    pub static ref BACKEND_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "kansas_backend_requests_total",
+            "Total requests routed to each backend"
+        ),
+        &["backend", "method"]
+    )
+    .unwrap();
+    pub static ref BACKEND_ERRORS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "kansas_backend_errors_total",
+            "Total failures to forward a request to each backend"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref BACKEND_RESPONSE_TIME: HistogramVec = register_histogram_vec!(
+        "kansas_backend_response_time_seconds",
+        "Response times of requests forwarded to each backend",
+        &["backend", "status"],
+        config().backend_buckets.clone(),
+    )
+    .unwrap();
+    pub static ref BACKEND_IN_FLIGHT: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_backend_in_flight_requests",
+            "Requests currently being forwarded to each backend"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref HTTP2_STREAMS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "kansas_http2_streams_total",
+            "Total HTTP/2 streams, on the listener or to the backends"
+        ),
+        &["side"]
+    )
+    .unwrap();
+    pub static ref HTTP2_ACTIVE_STREAMS: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_http2_active_streams",
+            "HTTP/2 streams currently open, on the listener or to the backends"
+        ),
+        &["side"]
+    )
+    .unwrap();
+    pub static ref ROUTING_ERRORS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "kansas_routing_errors_total",
+            "Total requests which could not be routed to a backend"
+        ),
+        &["kind", "route"]
+    )
+    .unwrap();
+    pub static ref BACKEND_HEALTHY: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_backend_healthy",
+            "Whether each backend is currently considered healthy"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref BACKEND_DRAINING: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_backend_draining",
+            "Whether each backend is shutting down, and taking no new queues"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref BACKEND_WEIGHT: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_backend_weight",
+            "The placement weight of each backend"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref BACKEND_CIRCUIT_STATE: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_backend_circuit_state",
+            "State of each backend's circuit breaker: 0 closed, 1 half-open, 2 open"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref BACKEND_CIRCUIT_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "kansas_backend_circuit_transitions_total",
+            "Total changes of each backend's circuit breaker, by new state"
+        ),
+        &["backend", "state"]
+    )
+    .unwrap();
+    pub static ref SHARD_QUEUES: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_shard_queues",
+            "Queues which each backend reports holding"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref SHARD_HANDLERS: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_shard_active_handlers",
+            "Active handlers which each backend reports"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref SHARD_MEMORY: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_shard_memory_bytes",
+            "Memory which each backend reports using"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref BACKEND_UNHEALTHY_STATUS: IntGaugeVec = register_int_gauge_vec!(
+        Opts::new(
+            "kansas_backend_unhealthy_status_code",
+            "HTTP status which marked each backend unhealthy; 0 if healthy or no response"
+        ),
+        &["backend"]
+    )
+    .unwrap();
+    pub static ref BACKEND_HEALTH_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        Opts::new(
+            "kansas_backend_health_transitions_total",
+            "Total changes in health of each backend"
+        ),
+        &["backend", "state"]
+    )
+    .unwrap();
+    pub static ref HEALTH_CHECK_TIME: HistogramVec = register_histogram_vec!(
+        "kansas_health_check_time_seconds",
+        "Response times of health checks to each backend",
+        &["backend", "state"],
+        vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0],
+    )
+    .unwrap();
+    pub static ref HEALTH_CHECK_LAST_SUCCESS: GaugeVec = register_gauge_vec!(
+        Opts::new(
+            "kansas_health_check_last_success_timestamp_seconds",
+            "Unix timestamp of the last successful health check of each backend"
+        ),
+        &["backend"]
+    )
+    .unwrap();
 }
 
-use prometheus::core::{Atomic, GenericGauge, Number};
+pub fn health_state_label(healthiness: &Healthiness) -> &'static str {
+    match healthiness {
+        Healthiness::Healthy => "healthy",
+        Healthiness::Draining => "draining",
+        Healthiness::Unresponsive(_) => "unresponsive",
+    }
+}
 
-pub struct GenericGaugeGuard<P: Atomic + 'static> {
+pub fn set_backend_health(backend: &str, healthiness: &Healthiness) {
+    let (healthy, draining, status) = match healthiness {
+        Healthiness::Healthy => (1, 0, 0),
+        Healthiness::Draining => (0, 1, 0),
+        Healthiness::Unresponsive(status) => (0, 0, status.map_or(0, |s| s.as_u16().into())),
+    };
+    BACKEND_HEALTHY.with_label_values(&[backend]).set(healthy);
+    BACKEND_DRAINING.with_label_values(&[backend]).set(draining);
+    BACKEND_UNHEALTHY_STATUS
+        .with_label_values(&[backend])
+        .set(status);
+}
+
+// Removes the series for a backend whose load is not known, rather than
+// reporting stale values
+pub fn set_shard_load(backend: &str, load: Option<&ShardLoad>) {
+    for (gauge, value) in [
+        (&*SHARD_QUEUES, load.map(|l| l.queues)),
+        (&*SHARD_HANDLERS, load.map(|l| l.handlers)),
+        (&*SHARD_MEMORY, load.map(|l| l.memory_bytes)),
+    ] {
+        match value {
+            Some(value) => gauge
+                .with_label_values(&[backend])
+                .set(value.try_into().unwrap_or(i64::MAX)),
+            None => {
+                let _ = gauge.remove_label_values(&[backend]);
+            }
+        }
+    }
+}
+
+pub fn set_circuit_state(backend: &str, state: CircuitState) {
+    let value = match state {
+        CircuitState::Closed => 0,
+        CircuitState::HalfOpen => 1,
+        CircuitState::Open => 2,
+    };
+    BACKEND_CIRCUIT_STATE
+        .with_label_values(&[backend])
+        .set(value);
+}
+
+// Reports the number of queues mapped to each backend, computed from
+// the queue store at scrape time.
+pub struct QueueCollector {
+    config: Arc<RuntimeConfig>,
+    queues: IntGaugeVec,
+}
+
+impl QueueCollector {
+    pub fn new(config: Arc<RuntimeConfig>) -> QueueCollector {
+        let queues = IntGaugeVec::new(
+            Opts::new(
+                "kansas_backend_queues",
+                "Queues currently mapped to each backend",
+            ),
+            &["backend"],
+        )
+        .unwrap();
+        QueueCollector { config, queues }
+    }
+}
+
+impl Collector for QueueCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.queues.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let counts = self.config.queue_store.count_by_port();
+
+        self.queues.reset();
+        for (address, backend) in self.config.backend.addresses.iter() {
+            let count = counts.get(&backend.port).copied().unwrap_or(0);
+            self.queues.with_label_values(&[address]).set(count as i64);
+        }
+        self.queues.collect()
+    }
+}
+
+use prometheus::core::{Atomic, AtomicI64, GenericGauge, Number};
+
+pub struct GenericGaugeGuard<P: Atomic> {
     value: P::T,
-    gauge: &'static GenericGauge<P>,
+    gauge: GenericGauge<P>,
 }
-impl<P: Atomic + 'static> Drop for GenericGaugeGuard<P> {
+impl<P: Atomic> Drop for GenericGaugeGuard<P> {
     fn drop(&mut self) {
         self.gauge.sub(self.value);
     }
 }
 
-pub trait GuardedGauge<P: Atomic + 'static> {
+pub trait GuardedGauge<P: Atomic> {
     #[must_use]
-    fn guarded_inc(&'static self) -> GenericGaugeGuard<P>;
+    fn guarded_inc(&self) -> GenericGaugeGuard<P>;
 }
 
-impl<P: Atomic + 'static> GuardedGauge<P> for GenericGauge<P> {
-    fn guarded_inc(&'static self) -> GenericGaugeGuard<P> {
+impl<P: Atomic> GuardedGauge<P> for GenericGauge<P> {
+    fn guarded_inc(&self) -> GenericGaugeGuard<P> {
         self.inc();
         GenericGaugeGuard {
             value: <P::T as Number>::from_i64(1),
-            gauge: self,
+            gauge: self.clone(),
         }
     }
 }
 
+// Counts an HTTP/2 stream, which is open until the guard is dropped
+pub fn http2_stream(side: &str) -> GenericGaugeGuard<AtomicI64> {
+    HTTP2_STREAMS.with_label_values(&[side]).inc();
+    HTTP2_ACTIVE_STREAMS
+        .with_label_values(&[side])
+        .guarded_inc()
+}
+
 pub fn handler() -> Result<Response<Body>, Error> {
     let encoder = TextEncoder::new();
     let metric_families = prometheus::gather();
@@ -68,21 +384,29 @@ pub fn handler() -> Result<Response<Body>, Error> {
         .unwrap())
 }
 
+// The inner future also says whether the request was redirected or
+// forwarded, if it was routed at all.
 pub async fn instrumented<Fut>(method: Method, inner: Fut) -> Result<Response<Body>, Error>
 where
-    Fut: Future<Output = Result<Response<Body>, Error>>,
+    Fut: Future<Output = Result<(Response<Body>, Option<RouteAction>), Error>>,
 {
     REQUESTS.with_label_values(&[method.as_str()]).inc();
     let _guard = OPEN_CONNECTIONS.guarded_inc();
     let now = Instant::now();
-    let result = inner.await;
-    if let Ok(ref response) = result {
-        let status = response.status().as_u16().to_string();
-        let attrs = &[method.as_str(), status.as_str()];
-        RESPONSES.with_label_values(attrs).inc();
-        RESPONSE_TIME
+    let (response, action) = inner.await?;
+    let elapsed = now.elapsed().as_secs_f64();
+    let status = response.status().as_u16().to_string();
+    let attrs = &[method.as_str(), status.as_str()];
+    RESPONSES.with_label_values(attrs).inc();
+    RESPONSE_TIME.with_label_values(attrs).observe(elapsed);
+    match action {
+        Some(RouteAction::Redirect) => REDIRECT_RESPONSE_TIME
+            .with_label_values(attrs)
+            .observe(elapsed),
+        Some(RouteAction::Forward) => FORWARD_RESPONSE_TIME
             .with_label_values(attrs)
-            .observe(now.elapsed().as_secs_f64());
+            .observe(elapsed),
+        None => {}
     }
-    result
+    Ok(response)
 }
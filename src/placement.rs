@@ -0,0 +1,414 @@
+use crate::{
+    handler::{Backend, BackendPool},
+    health::Healthiness,
+    queue_store::QueueStore,
+    state::BadBackendError,
+};
+use dashmap::DashMap;
+use log::info;
+use rand::Rng;
+use siphasher::sip::SipHasher13;
+use std::{collections::HashMap, fmt::Debug, hash::Hasher};
+
+// What is known about a queue creation request which does not name its
+// shard in `x-tornado-shard`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PlacementRequest<'a> {
+    pub realm: Option<&'a str>,
+}
+
+// A backend which can take new queues
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    pub address: &'a str,
+    pub port: u16,
+    // Never 0; backends without weight are not candidates
+    pub weight: u32,
+    pub queues: usize,
+}
+
+// Chooses the backend for a new queue from those which can take it,
+// which are in no particular order and may be none at all.
+pub trait PlacementPolicy: Debug + Send + Sync {
+    fn place(
+        &self,
+        request: &PlacementRequest,
+        candidates: &[Candidate],
+    ) -> Result<u16, BadBackendError>;
+
+    // Whether backends' weights affect where queues go, beyond a weight
+    // of 0 taking none
+    fn uses_weights(&self) -> bool {
+        true
+    }
+}
+
+// Django must name the shard in `x-tornado-shard`
+#[derive(Debug)]
+pub struct ExplicitHeader;
+
+impl PlacementPolicy for ExplicitHeader {
+    fn place(&self, _: &PlacementRequest, _: &[Candidate]) -> Result<u16, BadBackendError> {
+        Err(BadBackendError::BadRequest(
+            "No x-tornado-shard header".into(),
+        ))
+    }
+
+    fn uses_weights(&self) -> bool {
+        false
+    }
+}
+
+// Each realm goes to the same backend as long as the set of candidates
+// does not change, and only the realms of a backend which leaves move.
+// Uses weighted rendezvous hashing, so weights shift realms gradually.
+// The hash is SipHash-1-3 with fixed keys, so that realms stay put
+// across restarts and upgrades.  Requests without a realm are placed at
+// random.
+#[derive(Debug)]
+pub struct ConsistentHash;
+
+impl PlacementPolicy for ConsistentHash {
+    fn place(
+        &self,
+        request: &PlacementRequest,
+        candidates: &[Candidate],
+    ) -> Result<u16, BadBackendError> {
+        let realm = match request.realm {
+            Some(realm) => realm,
+            None => return WeightedRandom.place(request, candidates),
+        };
+        let scored = candidates.iter().map(|candidate| {
+            let mut hasher = SipHasher13::new();
+            hasher.write(realm.as_bytes());
+            hasher.write_u8(0);
+            hasher.write(candidate.address.as_bytes());
+            // Uniform in (0, 1], so that the logarithm is finite
+            let unit = (hasher.finish() as f64 + 1.0) / (u64::MAX as f64 + 1.0);
+            (f64::from(candidate.weight) / -unit.ln(), candidate.port)
+        });
+        best_by(scored, |score, best| score > best)
+    }
+}
+
+// The backend with the fewest queues for its weight
+#[derive(Debug)]
+pub struct LeastLoaded;
+
+impl PlacementPolicy for LeastLoaded {
+    fn place(
+        &self,
+        _: &PlacementRequest,
+        candidates: &[Candidate],
+    ) -> Result<u16, BadBackendError> {
+        let scored = candidates.iter().map(|candidate| {
+            (
+                candidate.queues as f64 / f64::from(candidate.weight),
+                candidate.port,
+            )
+        });
+        best_by(scored, |load, best| load < best)
+    }
+}
+
+// A backend at random, in proportion to its weight
+#[derive(Debug)]
+pub struct WeightedRandom;
+
+impl PlacementPolicy for WeightedRandom {
+    fn place(
+        &self,
+        _: &PlacementRequest,
+        candidates: &[Candidate],
+    ) -> Result<u16, BadBackendError> {
+        let total: u64 = candidates.iter().map(|c| u64::from(c.weight)).sum();
+        if total == 0 {
+            return Err(no_candidates());
+        }
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        for candidate in candidates {
+            let weight = u64::from(candidate.weight);
+            if pick < weight {
+                return Ok(candidate.port);
+            }
+            pick -= weight;
+        }
+        unreachable!("pick is less than the total weight")
+    }
+}
+
+// Realms listed in the configuration go to their backend, so that large
+// realms can be kept apart; other realms, or pinned ones whose backend
+// cannot take queues, are left to the fallback.
+#[derive(Debug)]
+pub struct Pinned {
+    // Realm to backend port
+    pub pins: HashMap<String, u16>,
+    pub fallback: Box<dyn PlacementPolicy>,
+}
+
+impl PlacementPolicy for Pinned {
+    fn place(
+        &self,
+        request: &PlacementRequest,
+        candidates: &[Candidate],
+    ) -> Result<u16, BadBackendError> {
+        let pinned = request
+            .realm
+            .and_then(|realm| self.pins.get(realm))
+            .filter(|port| candidates.iter().any(|c| c.port == **port));
+        match pinned {
+            Some(port) => Ok(*port),
+            None => self.fallback.place(request, candidates),
+        }
+    }
+
+    fn uses_weights(&self) -> bool {
+        self.fallback.uses_weights()
+    }
+}
+
+// The port with the best score; ties go to the lower port, so that the
+// choice is predictable
+fn best_by<I>(scored: I, better: impl Fn(f64, f64) -> bool) -> Result<u16, BadBackendError>
+where
+    I: Iterator<Item = (f64, u16)>,
+{
+    let mut best: Option<(f64, u16)> = None;
+    for (score, port) in scored {
+        if best.is_none_or(|(best_score, best_port)| {
+            better(score, best_score) || (score == best_score && port < best_port)
+        }) {
+            best = Some((score, port));
+        }
+    }
+    best.map(|(_, port)| port).ok_or_else(no_candidates)
+}
+
+fn no_candidates() -> BadBackendError {
+    BadBackendError::UnhealthyHost("no backend can take new queues".into())
+}
+
+#[derive(Debug)]
+pub struct PlacementConfig {
+    pub policy: Box<dyn PlacementPolicy>,
+    pub keep_realms_together: bool,
+    // The form field of the queue creation request naming its realm
+    pub realm_field: String,
+}
+
+// Chooses backends for new queues.  Remembers where each realm's queues
+// went, so that later queues from the same realm can join them.
+#[derive(Debug)]
+pub struct Placement {
+    pub config: PlacementConfig,
+    realms: DashMap<String, u16>,
+}
+
+impl Placement {
+    pub fn new(config: PlacementConfig) -> Placement {
+        Placement {
+            config,
+            realms: DashMap::new(),
+        }
+    }
+
+    pub fn choose(
+        &self,
+        pool: &BackendPool,
+        queue_store: &dyn QueueStore,
+        request: &PlacementRequest,
+    ) -> Result<u16, BadBackendError> {
+        let candidates = candidates(pool, queue_store);
+        let realm = request.realm.filter(|_| self.config.keep_realms_together);
+
+        // Stay with the realm's other queues, unless that shard can no
+        // longer take them
+        if let Some(realm) = realm {
+            if let Some(port) = self.realms.get(realm).map(|port| *port) {
+                if candidates.iter().any(|candidate| candidate.port == port) {
+                    return Ok(port);
+                }
+            }
+        }
+
+        let port = self.config.policy.place(request, &candidates)?;
+        if let Some(realm) = realm {
+            info!("Placing realm {} on port {}", realm, port);
+            self.realms.insert(realm.to_string(), port);
+        }
+        Ok(port)
+    }
+}
+
+fn candidates<'a>(pool: &'a BackendPool, queue_store: &dyn QueueStore) -> Vec<Candidate<'a>> {
+    let queues = queue_store.count_by_port();
+    pool.addresses
+        .iter()
+        .filter(|(_, backend)| accepts_queues(backend) && !backend.circuit_breaker.is_open())
+        .map(|(address, backend)| Candidate {
+            address,
+            port: backend.port,
+            weight: backend.weight(),
+            queues: queues.get(&backend.port).copied().unwrap_or(0),
+        })
+        .collect()
+}
+
+// A backend with no weight is being emptied, and keeps only the queues
+// it already has
+fn accepts_queues(backend: &Backend) -> bool {
+    **backend.healthiness.load() == Healthiness::Healthy && backend.weight() > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(address: &str, weight: u32, queues: usize) -> Candidate<'_> {
+        let port = address.rsplit(':').next().unwrap().parse().unwrap();
+        Candidate {
+            address,
+            port,
+            weight,
+            queues,
+        }
+    }
+
+    fn realm(realm: &str) -> PlacementRequest<'_> {
+        PlacementRequest { realm: Some(realm) }
+    }
+
+    #[test]
+    fn ties_go_to_the_lower_port() {
+        let scored = [(1.0, 9803), (1.0, 9801), (2.0, 9800), (1.0, 9802)];
+        assert_eq!(best_by(scored.into_iter(), |a, b| a < b).unwrap(), 9801);
+        assert_eq!(best_by(scored.into_iter(), |a, b| a > b).unwrap(), 9800);
+        assert!(best_by(std::iter::empty(), |a, b| a < b).is_err());
+    }
+
+    #[test]
+    fn least_loaded_divides_by_weight() {
+        let candidates = [
+            candidate("127.0.0.1:9800", 100, 10),
+            candidate("127.0.0.1:9801", 300, 20),
+        ];
+        let port = LeastLoaded.place(&PlacementRequest::default(), &candidates);
+        assert_eq!(port.unwrap(), 9801);
+
+        let candidates = [
+            candidate("127.0.0.1:9801", 100, 5),
+            candidate("127.0.0.1:9800", 100, 5),
+        ];
+        let port = LeastLoaded.place(&PlacementRequest::default(), &candidates);
+        assert_eq!(port.unwrap(), 9800);
+    }
+
+    #[test]
+    fn weighted_random_without_weight() {
+        let request = PlacementRequest::default();
+        assert!(WeightedRandom.place(&request, &[]).is_err());
+        let candidates = [
+            candidate("127.0.0.1:9800", 0, 0),
+            candidate("127.0.0.1:9801", 0, 0),
+        ];
+        assert!(WeightedRandom.place(&request, &candidates).is_err());
+    }
+
+    #[test]
+    fn weighted_random_skips_unweighted() {
+        let candidates = [
+            candidate("127.0.0.1:9800", 0, 0),
+            candidate("127.0.0.1:9801", 1, 0),
+        ];
+        for _ in 0..100 {
+            let port = WeightedRandom.place(&PlacementRequest::default(), &candidates);
+            assert_eq!(port.unwrap(), 9801);
+        }
+    }
+
+    #[test]
+    fn pinned_realms_go_to_their_backend() {
+        let policy = Pinned {
+            pins: HashMap::from([("7".to_string(), 9801)]),
+            fallback: Box::new(LeastLoaded),
+        };
+        let candidates = [
+            candidate("127.0.0.1:9800", 100, 0),
+            candidate("127.0.0.1:9801", 100, 50),
+        ];
+        assert_eq!(policy.place(&realm("7"), &candidates).unwrap(), 9801);
+        assert_eq!(policy.place(&realm("8"), &candidates).unwrap(), 9800);
+    }
+
+    #[test]
+    fn pinned_falls_back_without_its_backend() {
+        let policy = Pinned {
+            pins: HashMap::from([("7".to_string(), 9801)]),
+            fallback: Box::new(LeastLoaded),
+        };
+        let candidates = [
+            candidate("127.0.0.1:9800", 100, 3),
+            candidate("127.0.0.1:9802", 100, 1),
+        ];
+        assert_eq!(policy.place(&realm("7"), &candidates).unwrap(), 9802);
+
+        let policy = Pinned {
+            pins: HashMap::from([("7".to_string(), 9801)]),
+            fallback: Box::new(ExplicitHeader),
+        };
+        assert!(matches!(
+            policy.place(&realm("7"), &candidates),
+            Err(BadBackendError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn consistent_hash_is_stable() {
+        let candidates = [
+            candidate("127.0.0.1:9800", 100, 0),
+            candidate("127.0.0.1:9801", 100, 0),
+            candidate("127.0.0.1:9802", 100, 0),
+        ];
+        let first = ConsistentHash.place(&realm("42"), &candidates).unwrap();
+        let mut reordered = candidates.clone();
+        reordered.reverse();
+        for _ in 0..10 {
+            assert_eq!(
+                ConsistentHash.place(&realm("42"), &reordered).unwrap(),
+                first
+            );
+        }
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_realms_of_a_removed_backend() {
+        let candidates = [
+            candidate("127.0.0.1:9800", 100, 0),
+            candidate("127.0.0.1:9801", 100, 0),
+            candidate("127.0.0.1:9802", 100, 0),
+        ];
+        let remaining: Vec<Candidate> = candidates
+            .iter()
+            .filter(|c| c.port != 9801)
+            .cloned()
+            .collect();
+
+        let mut moved = 0;
+        for n in 0..300 {
+            let realm_id = n.to_string();
+            let before = ConsistentHash
+                .place(&realm(&realm_id), &candidates)
+                .unwrap();
+            let after = ConsistentHash.place(&realm(&realm_id), &remaining).unwrap();
+            if before == 9801 {
+                moved += 1;
+                assert_ne!(after, 9801);
+            } else {
+                assert_eq!(before, after, "realm {} moved", realm_id);
+            }
+        }
+        // Each backend gets a fair share of the realms
+        assert!((50..150).contains(&moved), "{} realms moved", moved);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// See https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LENGTH: usize = 107;
+
+// Reads a PROXY protocol v1 or v2 header from the start of the stream,
+// consuming exactly the header and nothing after it.  Returns the
+// original client address, or `None` if the header does not carry one
+// (a v1 `UNKNOWN` or v2 `LOCAL` connection, such as a health check from
+// the load-balancer itself).
+pub async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid_header("Missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S>(stream: &mut S, start: &[u8]) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // The header is short and we must not read past its end, so read a
+    // byte at a time until the CRLF.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid_header("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid_header("PROXY v1 header is not ASCII"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid_header("Bad PROXY v1 source address"))?;
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid_header("Bad PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid_header("Malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await?;
+    let mut payload = vec![0u8; length.into()];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid_header("Unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid_header("Unsupported PROXY v2 command")),
+    }
+
+    // The high nibble is the address family; the low nibble (stream or
+    // datagram) does not matter to us.  Anything after the addresses is
+    // TLVs, which we ignore.
+    match family >> 4 {
+        0x1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if payload.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[0..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // AF_UNSPEC, AF_UNIX
+        0x0 | 0x3 => Ok(None),
+        _ => Err(invalid_header("Malformed PROXY v2 address block")),
+    }
+}
+
+fn invalid_header(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    // Reads the header from the bytes, followed by a request, and checks
+    // that the request is all that is left
+    async fn read(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        let mut input = header.to_vec();
+        input.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        let mut stream = input.as_slice();
+        let address = read_header(&mut stream).await?;
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+        Ok(address)
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let address = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").await;
+        assert_eq!(address.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let address = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(
+            address.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let address = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(address.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_too_long() {
+        let mut header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443".to_vec();
+        header.resize(V1_MAX_LENGTH, b' ');
+        header.extend_from_slice(b"\r\n");
+        let error = read_header(&mut header.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v1_malformed() {
+        let error = read_header(&mut b"PROXY TCP4 192.0.2.1\r\n".as_slice())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv4() {
+        let header = v2_header(
+            0x1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        let address = read(&header).await;
+        assert_eq!(address.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv6() {
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let header = v2_header(0x1, 0x21, &addresses);
+        let address = read(&header).await;
+        assert_eq!(
+            address.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_with_tlvs() {
+        // A PP2_TYPE_AUTHORITY TLV after the addresses
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        addresses.extend_from_slice(&[0x02, 0x00, 0x03, b'a', b'.', b'b']);
+        let header = v2_header(0x1, 0x11, &addresses);
+        let address = read(&header).await;
+        assert_eq!(address.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_local_ipv4() {
+        let header = v2_header(
+            0x0,
+            0x11,
+            &[127, 0, 0, 1, 127, 0, 0, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        assert_eq!(read(&header).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_local_ipv6() {
+        let header = v2_header(0x0, 0x21, &[0; 36]);
+        assert_eq!(read(&header).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_short_address_block() {
+        // Claims IPv4, but only has room for the source address
+        let header = v2_header(0x1, 0x11, &[192, 0, 2, 1]);
+        let error = read_header(&mut header.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_truncated_stream() {
+        // The length says 12 bytes follow, but the stream ends first
+        let mut header = v2_header(
+            0x1,
+            0x11,
+            &[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb],
+        );
+        header.truncate(header.len() - 4);
+        let error = read_header(&mut header.as_slice()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn missing_header() {
+        let error = read_header(&mut b"GET / HTTP/1.1\r\n\r\n".as_slice())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
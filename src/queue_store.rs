@@ -0,0 +1,379 @@
+use dashmap::DashMap;
+use log::{error, info, warn};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+// Journals shorter than this are never compacted while running
+const COMPACT_MIN_LINES: usize = 1000;
+
+// Where each queue lives, by the port of its backend.  This is on the
+// request path, so implementations must answer quickly; a store backed
+// by something slow should keep a copy in memory and write through.
+pub trait QueueStore: Debug + Send + Sync {
+    fn get(&self, queue_id: &str) -> Option<u16>;
+
+    fn insert(&self, queue_id: &str, port: u16);
+
+    fn remove(&self, queue_id: &str) -> Option<u16>;
+
+    // Calls `f` with every queue and its port
+    fn for_each(&self, f: &mut dyn FnMut(&str, u16));
+
+    // Used for every queue placed, and every metrics scrape, so stores
+    // should override this if they can count without walking every queue
+    fn count_by_port(&self) -> HashMap<u16, usize> {
+        let mut counts = HashMap::new();
+        self.for_each(&mut |_, port| *counts.entry(port).or_default() += 1);
+        counts
+    }
+}
+
+// Forgets every queue on restart, after which Django must create them
+// again.
+#[derive(Debug, Default)]
+pub struct MemoryQueueStore {
+    queues: DashMap<String, u16>,
+    // Kept up to date on every change, so counting is cheap
+    counts: DashMap<u16, usize>,
+}
+
+impl MemoryQueueStore {
+    fn len(&self) -> usize {
+        self.queues.len()
+    }
+
+    fn count(&self, port: u16, added: bool) {
+        let mut count = self.counts.entry(port).or_default();
+        if added {
+            *count += 1;
+        } else {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+impl QueueStore for MemoryQueueStore {
+    fn get(&self, queue_id: &str) -> Option<u16> {
+        self.queues.get(queue_id).map(|port| *port)
+    }
+
+    fn insert(&self, queue_id: &str, port: u16) {
+        if let Some(replaced) = self.queues.insert(queue_id.to_string(), port) {
+            self.count(replaced, false);
+        }
+        self.count(port, true);
+    }
+
+    fn remove(&self, queue_id: &str) -> Option<u16> {
+        let (_, port) = self.queues.remove(queue_id)?;
+        self.count(port, false);
+        Some(port)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, u16)) {
+        for entry in self.queues.iter() {
+            f(entry.key(), *entry.value());
+        }
+    }
+
+    fn count_by_port(&self) -> HashMap<u16, usize> {
+        self.counts
+            .iter()
+            .filter(|count| *count.value() > 0)
+            .map(|count| (*count.key(), *count.value()))
+            .collect()
+    }
+}
+
+// Keeps the queues in memory, and appends every change to a file which
+// is replayed on startup, so that queues survive a restart.  The file is
+// written by a thread of its own, so that a slow disk never blocks the
+// runtime, and is rewritten with only the live queues on startup and
+// whenever most of its lines are for queues which have since gone.
+#[derive(Debug)]
+pub struct JournalQueueStore {
+    memory: Arc<MemoryQueueStore>,
+    // Unbounded, as a change which is not written is lost on restart
+    sender: Sender<String>,
+}
+
+#[derive(Debug)]
+struct Journal {
+    file: BufWriter<File>,
+    lines: usize,
+}
+
+impl JournalQueueStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<JournalQueueStore> {
+        let path = path.as_ref().to_path_buf();
+        let memory = Arc::new(MemoryQueueStore::default());
+        match File::open(&path) {
+            Ok(file) => replay(&memory, file)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let journal = compact(&path, &memory)?;
+        info!("Loaded {} queues from {}", journal.lines, path.display());
+
+        let (sender, receiver) = mpsc::channel();
+        let writer_memory = Arc::clone(&memory);
+        thread::Builder::new()
+            .name("queue-journal".to_string())
+            .spawn(move || write_journal(receiver, journal, &path, &writer_memory))?;
+        Ok(JournalQueueStore { memory, sender })
+    }
+
+    // The change is kept in memory even if the journal cannot be written,
+    // so that routing carries on; it is lost on restart.
+    fn append(&self, op: char, queue_id: &str, port: u16) {
+        if self.sender.send(journal_line(op, queue_id, port)).is_err() {
+            error!("Queue journal writer has stopped");
+        }
+    }
+}
+
+// Writes whatever has queued up, then flushes, and compacts once dead
+// lines outnumber live ones.  Compacting writes out the queues in
+// memory, which already hold any changes still queued, so those are
+// written twice, which replays the same.
+fn write_journal(
+    receiver: Receiver<String>,
+    mut journal: Journal,
+    path: &Path,
+    memory: &MemoryQueueStore,
+) {
+    while let Ok(line) = receiver.recv() {
+        let mut result = journal.append(&line);
+        while let Ok(line) = receiver.try_recv() {
+            result = result.and_then(|_| journal.append(&line));
+        }
+        if let Err(e) = result.and_then(|_| journal.file.flush()) {
+            error!("Failed to write queue journal {}: {}", path.display(), e);
+        }
+
+        if journal.lines >= COMPACT_MIN_LINES && journal.lines > 2 * memory.len() {
+            match compact(path, memory) {
+                Ok(compacted) => journal = compacted,
+                Err(e) => error!("Failed to compact queue journal {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+impl Journal {
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        self.file.write_all(line.as_bytes())?;
+        self.lines += 1;
+        Ok(())
+    }
+}
+
+// Writes out only the live queues, swaps that in for the journal, and
+// opens it for appending
+fn compact(path: &Path, memory: &MemoryQueueStore) -> io::Result<Journal> {
+    let compacted = path.with_extension("compacting");
+    let mut file = BufWriter::new(File::create(&compacted)?);
+    let mut lines = 0;
+    let mut result = Ok(());
+    memory.for_each(&mut |queue_id, port| {
+        if result.is_ok() {
+            result = file.write_all(journal_line('+', queue_id, port).as_bytes());
+            lines += 1;
+        }
+    });
+    result?;
+    file.into_inner()?.sync_all()?;
+    fs::rename(&compacted, path)?;
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok(Journal {
+        file: BufWriter::new(file),
+        lines,
+    })
+}
+
+// One change per line: `+ <port> <queue id>` or `- <port> <queue id>`.
+// The queue id comes last, as it is the only part which may hold spaces.
+fn journal_line(op: char, queue_id: &str, port: u16) -> String {
+    format!("{} {} {}\n", op, port, queue_id)
+}
+
+fn replay<R: Read>(memory: &MemoryQueueStore, reader: R) -> io::Result<()> {
+    for (number, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        let mut parts = line.splitn(3, ' ');
+        let parsed = match (parts.next(), parts.next(), parts.next()) {
+            (Some(op), Some(port), Some(queue_id)) => {
+                port.parse::<u16>().ok().map(|port| (op, port, queue_id))
+            }
+            _ => None,
+        };
+        match parsed {
+            Some(("+", port, queue_id)) => memory.insert(queue_id, port),
+            Some(("-", _, queue_id)) => {
+                memory.remove(queue_id);
+            }
+            // Most likely a line cut short by a crash
+            _ => warn!("Skipping bad queue journal line {}: {:?}", number + 1, line),
+        }
+    }
+    Ok(())
+}
+
+impl QueueStore for JournalQueueStore {
+    fn get(&self, queue_id: &str) -> Option<u16> {
+        self.memory.get(queue_id)
+    }
+
+    fn insert(&self, queue_id: &str, port: u16) {
+        self.memory.insert(queue_id, port);
+        self.append('+', queue_id, port);
+    }
+
+    fn remove(&self, queue_id: &str) -> Option<u16> {
+        let port = self.memory.remove(queue_id)?;
+        self.append('-', queue_id, port);
+        Some(port)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, u16)) {
+        self.memory.for_each(f)
+    }
+
+    fn count_by_port(&self) -> HashMap<u16, usize> {
+        self.memory.count_by_port()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A journal path of its own for each test, as they run in parallel
+    fn journal_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kansas-{}-{}.journal", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn queues(store: &dyn QueueStore) -> HashMap<String, u16> {
+        let mut queues = HashMap::new();
+        store.for_each(&mut |queue_id, port| {
+            queues.insert(queue_id.to_string(), port);
+        });
+        queues
+    }
+
+    #[test]
+    fn counts_follow_replaced_and_removed_queues() {
+        let store = MemoryQueueStore::default();
+        store.insert("9800:1", 9800);
+        store.insert("9800:2", 9800);
+        store.insert("9801:1", 9801);
+        assert_eq!(store.count_by_port(), HashMap::from([(9800, 2), (9801, 1)]));
+
+        store.insert("9800:2", 9801);
+        assert_eq!(store.count_by_port(), HashMap::from([(9800, 1), (9801, 2)]));
+
+        assert_eq!(store.remove("9800:1"), Some(9800));
+        assert_eq!(store.remove("9800:1"), None);
+        assert_eq!(store.count_by_port(), HashMap::from([(9801, 2)]));
+    }
+
+    #[test]
+    fn replays_changes_in_order() {
+        let memory = MemoryQueueStore::default();
+        let journal = "+ 9800 9800:1\n+ 9801 9801:1\n+ 9800 a queue\n- 9800 9800:1\n";
+        replay(&memory, journal.as_bytes()).unwrap();
+        assert_eq!(
+            queues(&memory),
+            HashMap::from([("9801:1".to_string(), 9801), ("a queue".to_string(), 9800)])
+        );
+    }
+
+    #[test]
+    fn replay_skips_bad_lines() {
+        let memory = MemoryQueueStore::default();
+        let journal = "+ 9800 9800:1\n? 9800 9800:2\n+ nope 9800:3\n\n+ 9801 9801:1\n+ 98";
+        replay(&memory, journal.as_bytes()).unwrap();
+        assert_eq!(
+            queues(&memory),
+            HashMap::from([("9800:1".to_string(), 9800), ("9801:1".to_string(), 9801)])
+        );
+    }
+
+    #[test]
+    fn compact_keeps_only_live_queues() {
+        let path = journal_path("compact");
+        let memory = MemoryQueueStore::default();
+        memory.insert("9800:1", 9800);
+        memory.insert("9801:1", 9801);
+        let mut journal = compact(&path, &memory).unwrap();
+        assert_eq!(journal.lines, 2);
+        journal.append("- 9800 9800:1\n").unwrap();
+        journal.file.flush().unwrap();
+
+        let replayed = MemoryQueueStore::default();
+        replay(&replayed, File::open(&path).unwrap()).unwrap();
+        assert_eq!(
+            queues(&replayed),
+            HashMap::from([("9801:1".to_string(), 9801)])
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn writer_compacts_once_most_lines_are_dead() {
+        let path = journal_path("writer");
+        let memory = MemoryQueueStore::default();
+        let journal = compact(&path, &memory).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..COMPACT_MIN_LINES {
+            let queue_id = format!("9800:{}", i);
+            memory.insert(&queue_id, 9800);
+            sender.send(journal_line('+', &queue_id, 9800)).unwrap();
+        }
+        for i in 10..COMPACT_MIN_LINES {
+            let queue_id = format!("9800:{}", i);
+            memory.remove(&queue_id);
+            sender.send(journal_line('-', &queue_id, 9800)).unwrap();
+        }
+        drop(sender);
+        write_journal(receiver, journal, &path, &memory);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 10);
+        let replayed = MemoryQueueStore::default();
+        replay(&replayed, contents.as_bytes()).unwrap();
+        assert_eq!(queues(&replayed), queues(&memory));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reopening_restores_queues() {
+        let path = journal_path("reopen");
+        fs::write(
+            &path,
+            "+ 9800 9800:1\n+ 9801 9801:1\n- 9800 9800:1\n+ 9800 9800:2",
+        )
+        .unwrap();
+        let store = JournalQueueStore::open(&path).unwrap();
+        assert_eq!(store.get("9801:1"), Some(9801));
+        assert_eq!(store.get("9800:1"), None);
+        assert_eq!(store.get("9800:2"), Some(9800));
+        assert_eq!(store.count_by_port(), HashMap::from([(9800, 1), (9801, 1)]));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,31 +1,146 @@
-use crate::{configuration::RuntimeConfig, handler::MainService};
-use dashmap::DashMap;
-use futures::TryFutureExt;
-use hyper::server::conn::AddrStream;
-use hyper::{service::make_service_fn, Server};
-use std::{io, sync::Arc};
+use crate::{
+    configuration::RuntimeConfig,
+    handler::MainService,
+    metrics::QueueCollector,
+    proxy_protocol,
+    tls::{self, ReloadableCertificate},
+};
+use hyper::server::conn::Http;
+use log::{debug, error, warn};
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    time::{sleep, timeout},
+};
+
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Errors from accept() which are down to a client which has already
+// gone, as opposed to running out of resources
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
 
 pub async fn create(config: Arc<RuntimeConfig>) -> Result<(), io::Error> {
-    let queue_map: Arc<DashMap<String, u16>> = Arc::new(DashMap::new());
-    let address = config.listen_address;
-    let service = make_service_fn(move |stream: &AddrStream| {
-        let client_address = stream.remote_addr();
-        let config = Arc::clone(&config);
-        let queue_map = Arc::clone(&queue_map);
+    prometheus::register(Box::new(QueueCollector::new(Arc::clone(&config)))).unwrap();
+    let listener = TcpListener::bind(config.listen_address)
+        .await
+        .map_err(|e| {
+            let msg = format!("Failed to listen server: {}", e);
+            io::Error::other(msg)
+        })?;
+    let tls_acceptor = match &config.tls {
+        None => None,
+        Some(tls_config) => {
+            let certificate = Arc::new(ReloadableCertificate::load(tls_config)?);
+            let reloadable = Arc::clone(&certificate);
+            tokio::spawn(async move {
+                if let Err(e) = tls::reload_on_sighup(reloadable).await {
+                    error!("Failed to listen for SIGHUP: {}", e);
+                }
+            });
+            Some(tls::acceptor(certificate))
+        }
+    };
+    // Plain connections are HTTP/1 unless they open with the HTTP/2
+    // preface (h2c with prior knowledge); over TLS, ALPN decides.
+    let http = Arc::new(Http::new());
+    let mut http2_only = Http::new();
+    http2_only.http2_only(true);
+    let http2_only = Arc::new(http2_only);
 
-        async move {
-            Ok::<_, io::Error>(MainService {
+    loop {
+        let (stream, peer_address) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            // Only that one client is affected, so carry on straight away
+            Err(e) if is_connection_error(&e) => {
+                debug!("Failed to accept connection: {}", e);
+                continue;
+            }
+            Err(e) => {
+                // Most likely out of file descriptors; back off rather
+                // than spinning on the error.
+                error!("Failed to accept connection: {}", e);
+                sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let config = Arc::clone(&config);
+        let http = Arc::clone(&http);
+        let http2_only = Arc::clone(&http2_only);
+        let tls_acceptor = tls_acceptor.clone();
+        tokio::spawn(async move {
+            let (stream, client_address) =
+                match accept_client(stream, peer_address, config.proxy_protocol).await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Dropping connection from {}: {}", peer_address, e);
+                        return;
+                    }
+                };
+            let service = MainService {
                 client_address,
+                secure: tls_acceptor.is_some(),
                 config,
-                queue_map,
-            })
-        }
-    });
-    Server::bind(&address)
-        .serve(service)
-        .map_err(|e| {
-            let msg = format!("Failed to listen server: {}", e);
-            io::Error::new(io::ErrorKind::Other, msg)
-        })
-        .await
+            };
+            match tls_acceptor {
+                None => serve(&http, stream, service).await,
+                Some(acceptor) => {
+                    let stream = match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            debug!("TLS handshake with {} failed: {}", client_address, e);
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("TLS handshake with {} timed out", client_address);
+                            return;
+                        }
+                    };
+                    if stream.get_ref().1.alpn_protocol() == Some(b"h2") {
+                        serve(&http2_only, stream, service).await
+                    } else {
+                        serve(&http, stream, service).await
+                    }
+                }
+            }
+        });
+    }
+}
+
+async fn serve<I>(http: &Http, stream: I, service: MainService)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let client_address = service.client_address;
+    if let Err(e) = http.serve_connection(stream, service).await {
+        debug!("Error serving connection from {}: {}", client_address, e);
+    }
+}
+
+// Determines the address of the client, which is the peer unless the
+// connection starts with a PROXY protocol header naming someone else.
+async fn accept_client(
+    mut stream: TcpStream,
+    peer_address: SocketAddr,
+    proxy_protocol: bool,
+) -> Result<(TcpStream, SocketAddr), io::Error> {
+    if !proxy_protocol {
+        return Ok((stream, peer_address));
+    }
+    let source = timeout(
+        PROXY_HEADER_TIMEOUT,
+        proxy_protocol::read_header(&mut stream),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No PROXY header received"))??;
+    Ok((stream, source.unwrap_or(peer_address)))
 }
@@ -1,11 +1,16 @@
-use crate::{handler::BackendPool, health::Healthiness};
+use crate::{
+    handler::BackendPool,
+    health::Healthiness,
+    placement::{Placement, PlacementRequest},
+    queue_store::QueueStore,
+};
 use anyhow::Result;
 use bytes::Bytes;
-use dashmap::DashMap;
 use hyper::{Body, Method, Request, Response};
 use log::{debug, info};
 use std::mem;
 use thiserror::Error;
+use tracing::instrument;
 use url::form_urlencoded;
 
 #[derive(Error, Debug)]
@@ -16,6 +21,12 @@ pub enum BadBackendError {
     #[error("Unhealthy backend: {0}")]
     UnhealthyHost(String),
 
+    #[error("Circuit open for backend: {0}")]
+    CircuitOpen(String),
+
+    #[error("Draining backend: {0}")]
+    DrainingHost(String),
+
     #[error("Unknown backend: {0}")]
     UnknownHost(String),
 
@@ -23,6 +34,32 @@ pub enum BadBackendError {
     UnknownQueue(String),
 }
 
+impl BadBackendError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BadBackendError::BadRequest(_) => "BadRequest",
+            BadBackendError::UnhealthyHost(_) => "UnhealthyHost",
+            BadBackendError::DrainingHost(_) => "DrainingHost",
+            BadBackendError::CircuitOpen(_) => "CircuitOpen",
+            BadBackendError::UnknownHost(_) => "UnknownHost",
+            BadBackendError::UnknownQueue(_) => "UnknownQueue",
+        }
+    }
+}
+
+// A low-cardinality name for what the request is trying to do, for
+// use in metrics labels.
+pub fn request_route(request: &Request<Body>) -> &'static str {
+    if request.uri().path() == "/api/v1/events/internal" {
+        return "create_queue";
+    }
+    match *request.method() {
+        Method::GET => "get_events",
+        Method::DELETE => "delete_queue",
+        _ => "other",
+    }
+}
+
 // This RAII wrapper streams a request body into memory so we can
 // examine it; when the wrapper is dropped, we stuff the body back
 // into the request so it can be forwarded to the backend.
@@ -46,22 +83,36 @@ impl Drop for PeekBody<'_> {
     }
 }
 
+#[instrument(skip_all)]
 async fn get_port(
-    queue_map: &DashMap<String, u16>,
+    pool: &BackendPool,
+    placement: &Placement,
+    queue_store: &dyn QueueStore,
     request: &mut Request<Body>,
 ) -> Result<u16, BadBackendError> {
     if request.uri().path() == "/api/v1/events/internal" {
-        let port_header = request
-            .headers()
-            .get("x-tornado-shard")
-            .ok_or_else(|| BadBackendError::BadRequest("No x-tornado-shard header".into()))?;
-        let port_str = port_header
-            .to_str()
-            .map_err(|_| BadBackendError::BadRequest("Cannot convert header to string".into()))?;
-        info!("Creating new queue on port {}", port_str);
-        Ok(port_str
-            .parse::<u16>()
-            .map_err(|_| BadBackendError::BadRequest("Failed to parse port as int".into()))?)
+        let port = match request.headers().get("x-tornado-shard") {
+            Some(port_header) => port_header
+                .to_str()
+                .map_err(|_| BadBackendError::BadRequest("Cannot convert header to string".into()))?
+                .parse::<u16>()
+                .map_err(|_| BadBackendError::BadRequest("Failed to parse port as int".into()))?,
+            None => {
+                let peek_body = PeekBody::new(request.body_mut()).await.map_err(|_| {
+                    BadBackendError::BadRequest("Failed to read request body".into())
+                })?;
+                let realm = form_urlencoded::parse(&peek_body.bytes)
+                    .into_owned()
+                    .find(|pair| pair.0 == placement.config.realm_field)
+                    .map(|pair| pair.1);
+                let placement_request = PlacementRequest {
+                    realm: realm.as_deref(),
+                };
+                placement.choose(pool, queue_store, &placement_request)?
+            }
+        };
+        info!("Creating new queue on port {}", port);
+        Ok(port)
     } else {
         let peek_body;
         let body_bytes = match *request.method() {
@@ -88,39 +139,52 @@ async fn get_port(
             .find(|pair| pair.0 == "queue_id")
             .ok_or_else(|| BadBackendError::UnknownQueue("(missing)".into()))?
             .1;
-        let queue_backend = queue_map
+        let port = queue_store
             .get(&queue_id)
-            .ok_or(BadBackendError::UnknownQueue(queue_id))?;
-        debug!(
-            "Routing queue {} to port {}",
-            queue_backend.key(),
-            *queue_backend
-        );
-        Ok(*queue_backend)
+            .ok_or_else(|| BadBackendError::UnknownQueue(queue_id.clone()))?;
+        debug!("Routing queue {} to port {}", queue_id, port);
+        Ok(port)
     }
 }
 
+#[instrument(skip_all)]
 pub async fn choose_backend(
     pool: &BackendPool,
-    queue_map: &DashMap<String, u16>,
+    placement: &Placement,
+    queue_store: &dyn QueueStore,
     request: &mut Request<Body>,
 ) -> Result<(u16, String), BadBackendError> {
-    let port = get_port(queue_map, request).await?;
-    let backend = format!("127.0.0.1:{}", port);
-    let health = pool
-        .addresses
-        .get(&backend)
-        .ok_or_else(|| BadBackendError::UnknownHost(backend.clone()))?;
-    if **health.load() != Healthiness::Healthy {
+    let port = get_port(pool, placement, queue_store, request).await?;
+    let (address, backend) = pool
+        .backend_for_port(port)
+        .ok_or_else(|| BadBackendError::UnknownHost(format!("port {}", port)))?;
+    match **backend.healthiness.load() {
         // Backend is down, stall for time?
-        Err(BadBackendError::UnhealthyHost(backend))
+        Healthiness::Unresponsive(_) => {
+            return Err(BadBackendError::UnhealthyHost(address.clone()))
+        }
+        // It would be gone with the backend, shortly
+        Healthiness::Draining if request_route(request) == "create_queue" => {
+            return Err(BadBackendError::DrainingHost(address.clone()))
+        }
+        _ => {}
+    }
+    // We never see the result of a redirect, so only forwarded requests
+    // can be trials when the circuit is half-open
+    let allowed = if request.method() == Method::GET {
+        !backend.circuit_breaker.is_open()
     } else {
-        Ok((port, backend))
+        backend.circuit_breaker.allow(address)
+    };
+    if !allowed {
+        return Err(BadBackendError::CircuitOpen(address.clone()));
     }
+    Ok((port, address.clone()))
 }
 
+#[instrument(skip(queue_store, resp))]
 pub fn store_backend(
-    queue_map: &DashMap<String, u16>,
+    queue_store: &dyn QueueStore,
     method: Method,
     resp: &Response<Body>,
     port: u16,
@@ -130,10 +194,10 @@ pub fn store_backend(
             if let Ok(queue_id) = queue_header.to_str() {
                 if method == Method::DELETE {
                     info!("Removed queue {} from port {}", queue_id, port);
-                    queue_map.remove(queue_id).unwrap();
+                    queue_store.remove(queue_id);
                 } else {
                     info!("Created new queue {} on port {}", queue_id, port);
-                    queue_map.insert(queue_id.to_string(), port);
+                    queue_store.insert(queue_id, port);
                 }
             }
         }
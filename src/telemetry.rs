@@ -0,0 +1,62 @@
+use opentelemetry::{
+    global,
+    sdk::{propagation::TraceContextPropagator, trace, Resource},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing_subscriber::{filter::Targets, prelude::*};
+
+#[derive(Debug)]
+pub struct TracingConfig {
+    pub otlp_endpoint: Option<String>,
+    pub service_name: String,
+    pub console: bool,
+}
+
+// Installs the global tracing subscriber: a formatter for warnings
+// from libraries which use `tracing` rather than `log`; if an OTLP
+// endpoint is configured, an exporter of spans to that collector; and
+// the tokio console layer, if enabled and compiled in.
+pub fn init(config: &TracingConfig) -> Result<(), TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let otlp_layer = match &config.otlp_endpoint {
+        None => None,
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", config.service_name.clone()),
+                ])))
+                .install_batch(opentelemetry::runtime::Tokio)?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+    };
+
+    let fmt_filter = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|filter| filter.parse::<Targets>().ok())
+        .unwrap_or_else(|| Targets::new().with_default(tracing::Level::ERROR));
+
+    let registry = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(fmt_filter))
+        .with(otlp_layer);
+
+    #[cfg(feature = "console")]
+    let registry = registry.with(config.console.then(console_subscriber::spawn));
+    #[cfg(not(feature = "console"))]
+    if config.console {
+        log::warn!(
+            "The tokio console was enabled, but kansas was built without the `console` feature"
+        );
+    }
+
+    registry.init();
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use arc_swap::ArcSwap;
+use log::{error, info};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig,
+};
+use rustls_pemfile::Item;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub certificate_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+// Serves whichever certificate was most recently loaded, so that it can
+// be replaced without restarting.
+pub struct ReloadableCertificate {
+    config: TlsConfig,
+    key: ArcSwap<CertifiedKey>,
+}
+
+impl ReloadableCertificate {
+    pub fn load(config: &TlsConfig) -> io::Result<ReloadableCertificate> {
+        Ok(ReloadableCertificate {
+            config: config.clone(),
+            key: ArcSwap::from_pointee(load_certified_key(config)?),
+        })
+    }
+
+    pub fn reload(&self) -> io::Result<()> {
+        self.key.store(Arc::new(load_certified_key(&self.config)?));
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.load_full())
+    }
+}
+
+pub fn acceptor(certificate: Arc<ReloadableCertificate>) -> TlsAcceptor {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certificate);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(config))
+}
+
+pub async fn reload_on_sighup(certificate: Arc<ReloadableCertificate>) -> io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match certificate.reload() {
+            Ok(()) => info!("Reloaded TLS certificate"),
+            Err(e) => error!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn load_certified_key(config: &TlsConfig) -> io::Result<CertifiedKey> {
+    let certificates = read_certificates(&config.certificate_path)?;
+    let key = read_private_key(&config.key_path)?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| invalid_pem(&config.key_path, "unsupported private key type"))?;
+
+    Ok(CertifiedKey::new(certificates, signing_key))
+}
+
+// TLS settings for connections to the backends
+#[derive(Debug, Clone, Default)]
+pub struct BackendTlsConfig {
+    pub ca_bundle_path: Option<PathBuf>,
+    pub client_certificate_path: Option<PathBuf>,
+    pub client_key_path: Option<PathBuf>,
+}
+
+impl BackendTlsConfig {
+    // Trusts the CA bundle if one is given, and the system's roots
+    // otherwise; and presents the client certificate, if any.
+    pub fn client_config(&self) -> io::Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_bundle_path {
+            Some(path) => {
+                for certificate in read_certificates(path)? {
+                    roots
+                        .add(&certificate)
+                        .map_err(|e| invalid_pem(path, &e.to_string()))?;
+                }
+            }
+            None => {
+                for certificate in rustls_native_certs::load_native_certs()? {
+                    // Skip any which rustls cannot parse, as hyper-rustls does
+                    let _ = roots.add(&Certificate(certificate.0));
+                }
+            }
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        match (&self.client_certificate_path, &self.client_key_path) {
+            (Some(certificate_path), Some(key_path)) => builder
+                .with_single_cert(
+                    read_certificates(certificate_path)?,
+                    read_private_key(key_path)?,
+                )
+                .map_err(|e| invalid_pem(key_path, &e.to_string())),
+            (None, None) => Ok(builder.with_no_client_auth()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "A client certificate and key must be given together",
+            )),
+        }
+    }
+}
+
+fn read_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certificates: Vec<Certificate> = read_pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certificates.is_empty() {
+        return Err(invalid_pem(path, "no certificates"));
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> io::Result<PrivateKey> {
+    read_pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_pem(path, "no private key"))
+}
+
+fn read_pem(path: &Path) -> io::Result<Vec<Item>> {
+    let file = File::open(path).map_err(|e| {
+        io::Error::new(e.kind(), format!("Error reading {}: {}", path.display(), e))
+    })?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))
+}
+
+fn invalid_pem(path: &Path, msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Error loading {}: {}", path.display(), msg),
+    )
+}